@@ -0,0 +1,259 @@
+//! Detecting the public IPv4 and IPv6 address, from "what is my IP" web
+//! services, the router (UPnP) or a network interface.
+
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+use log::debug;
+use reqwest::blocking::Client;
+
+use crate::Retry;
+
+pub const DEFAULT_IPV4_PROVIDERS: &[&str] = &[
+    "https://ipv4.icanhazip.com",
+    "https://api.ipify.org",
+    "https://ifconfig.co/ip",
+];
+pub const DEFAULT_IPV6_PROVIDERS: &[&str] = &[
+    "https://ipv6.icanhazip.com",
+    "https://api64.ipify.org",
+    "https://ifconfig.co/ip",
+];
+
+/// How long a single provider may take before the next one is tried, unless
+/// --timeout is shorter.
+pub const PROVIDER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// No provider told us the public address. Like a network error, this may
+/// go away on its own.
+#[derive(Debug)]
+pub struct DetectionFailed(String);
+
+impl std::fmt::Display for DetectionFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DetectionFailed {}
+
+/// "What is my IP" services, tried in order until enough of them agree.
+pub struct Providers {
+    pub v4: Vec<String>,
+    pub v6: Vec<String>,
+    /// Matching answers needed per family; 1 takes the first answer.
+    pub v4_consensus: usize,
+    pub v6_consensus: usize,
+    pub timeout: Duration,
+}
+
+impl Providers {
+    /// Uses the flag if given, else the config file's list, else the
+    /// environment variable, else the defaults.
+    pub fn resolve(flag: &[String], config: Option<Vec<String>>, env_var: &str, defaults: &[&str]) -> Vec<String> {
+        if !flag.is_empty() {
+            return flag.to_vec();
+        }
+        if let Some(list) = config {
+            return list;
+        }
+        match env::var(env_var) {
+            Ok(list) => list.split(',').map(str::trim).filter(|u| !u.is_empty()).map(str::to_string).collect(),
+            Err(_) => defaults.iter().map(|u| u.to_string()).collect(),
+        }
+    }
+}
+
+/// Asks each provider in turn and returns the first `T` address that
+/// `consensus` providers agree on. Dual-stack endpoints may answer with the
+/// wrong family, which fails to parse and moves on to the next provider.
+pub fn detect_ip<T>(client: &Client, providers: &[String], consensus: usize, timeout: Duration, family: &str) -> Result<String, String>
+where
+    T: std::str::FromStr + std::fmt::Display,
+{
+    let mut votes: Vec<(String, usize)> = Vec::new();
+    for url in providers {
+        let body = client.get(url)
+            .timeout(timeout)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.text());
+        match body {
+            Ok(body) => match body.trim().parse::<T>() {
+                Ok(ip) => {
+                    debug!("Detected {} {} via {}", family, ip, url);
+                    let ip = ip.to_string();
+                    let count = match votes.iter_mut().find(|(seen, _)| *seen == ip) {
+                        Some((_, count)) => {
+                            *count += 1;
+                            *count
+                        }
+                        None => {
+                            votes.push((ip.clone(), 1));
+                            1
+                        }
+                    };
+                    if count >= consensus {
+                        return Ok(ip);
+                    }
+                }
+                Err(_) => debug!("{} returned no {} address: {:?}", url, family, body.trim()),
+            },
+            Err(e) => debug!("{} failed: {}", url, e),
+        }
+    }
+    if votes.is_empty() {
+        return Err(format!("❌ Could not detect the public {} address from any provider ({})", family, providers.join(", ")));
+    }
+    let answers: Vec<String> = votes.iter().map(|(ip, count)| format!("{} ×{}", ip, count)).collect();
+    Err(format!("❌ Fewer than {} providers agree on the public {} address ({})", consensus, family, answers.join(", ")))
+}
+
+pub fn detect_ipv4(client: &Client, providers: &Providers, retry: &Retry) -> Result<String, Box<dyn std::error::Error>> {
+    retry.run("IPv4 detection", || detect_ip::<Ipv4Addr>(client, &providers.v4, providers.v4_consensus, providers.timeout, "IPv4"))
+        .map_err(|e| DetectionFailed(e).into())
+}
+
+pub fn detect_ipv6(client: &Client, providers: &Providers, retry: &Retry) -> Option<String> {
+    retry.run("IPv6 detection", || detect_ip::<Ipv6Addr>(client, &providers.v6, providers.v6_consensus, providers.timeout, "IPv6")).ok()
+}
+
+/// Asks the router for its WAN address via UPnP IGD. IPv4 only.
+pub fn detect_ipv4_upnp() -> Result<String, Box<dyn std::error::Error>> {
+    let options = igd_next::SearchOptions {
+        timeout: Some(Duration::from_secs(3)),
+        ..Default::default()
+    };
+    let gateway = igd_next::search_gateway(options)?;
+    match gateway.get_external_ip()? {
+        IpAddr::V4(ip) => Ok(ip.to_string()),
+        IpAddr::V6(ip) => Err(format!("router reported an IPv6 external address: {}", ip).into()),
+    }
+}
+
+/// Picks the first public IPv4 and the first global IPv6 address of the
+/// named interface, preferring stable IPv6 addresses over temporary ones.
+pub fn interface_addresses(name: &str) -> Result<(Option<Ipv4Addr>, Option<Ipv6Addr>), Box<dyn std::error::Error>> {
+    let addrs: Vec<IpAddr> = if_addrs::get_if_addrs()
+        .map_err(|e| format!("❌ Could not list network interfaces: {}", e))?
+        .into_iter()
+        .filter(|iface| iface.name == name)
+        .map(|iface| iface.ip())
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("❌ Network interface {} not found or has no addresses", name).into());
+    }
+
+    let ip4 = addrs.iter().find_map(|ip| match ip {
+        IpAddr::V4(ip) if is_public_ipv4(ip) => Some(*ip),
+        _ => None,
+    });
+    let mut ip6: Vec<Ipv6Addr> = addrs.iter().filter_map(|ip| match ip {
+        IpAddr::V6(ip) if is_global_ipv6(ip) => Some(*ip),
+        _ => None,
+    }).collect();
+    let temporary = temporary_ipv6_addresses();
+    // Stable sort: keeps interface order among equally preferred addresses.
+    ip6.sort_by_key(|ip| temporary.contains(ip));
+
+    Ok((ip4, ip6.first().copied()))
+}
+
+/// Temporary (privacy extension) IPv6 addresses, from /proc/net/if_inet6.
+#[cfg(target_os = "linux")]
+fn temporary_ipv6_addresses() -> Vec<Ipv6Addr> {
+    const IFA_F_TEMPORARY: u32 = 0x01;
+
+    let Ok(table) = std::fs::read_to_string("/proc/net/if_inet6") else {
+        return Vec::new();
+    };
+    table.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let flags = u32::from_str_radix(fields.get(4)?, 16).ok()?;
+        if flags & IFA_F_TEMPORARY == 0 {
+            return None;
+        }
+        u128::from_str_radix(fields.first()?, 16).ok().map(Ipv6Addr::from)
+    }).collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn temporary_ipv6_addresses() -> Vec<Ipv6Addr> {
+    Vec::new()
+}
+
+/// Returns true for global unicast IPv6 addresses, skipping loopback,
+/// link-local (fe80::/10), unique local (fc00::/7) and multicast.
+pub fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || (first & 0xffc0) == 0xfe80
+        || (first & 0xfe00) == 0xfc00)
+}
+
+/// Like `is_global_ipv6`, but also rules out the documentation prefix
+/// 2001:db8::/32.
+pub fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    is_global_ipv6(ip) && !(segments[0] == 0x2001 && segments[1] == 0x0db8)
+}
+
+/// Returns true for addresses that are routable on the public internet.
+/// Shared address space (100.64.0.0/10) is treated as public here; it is
+/// carrier-grade NAT rather than a LAN address.
+pub fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_ip_waits_for_consensus() {
+        let mut server = mockito::Server::new();
+        for (path, body) in [("/a", "203.0.113.7\n"), ("/b", "198.51.100.1\n"), ("/c", "203.0.113.7\n"), ("/d", "198.51.100.1\n")] {
+            server.mock("GET", path).with_body(body).create();
+        }
+        let providers: Vec<String> = ["/a", "/b", "/c", "/d"].iter().map(|p| format!("{}{}", server.url(), p)).collect();
+        let detect = |consensus| detect_ip::<Ipv4Addr>(&Client::new(), &providers, consensus, PROVIDER_TIMEOUT, "IPv4");
+
+        assert_eq!(detect(1).unwrap(), "203.0.113.7");
+        assert_eq!(detect(2).unwrap(), "203.0.113.7");
+        assert_eq!(
+            detect(3).unwrap_err(),
+            "❌ Fewer than 3 providers agree on the public IPv4 address (203.0.113.7 ×2, 198.51.100.1 ×2)",
+        );
+    }
+
+    #[test]
+    fn detect_ip_falls_back_past_failing_and_wrong_family_providers() {
+        let mut server = mockito::Server::new();
+        let failing = server.mock("GET", "/a").with_status(500).create();
+        let wrong_family = server.mock("GET", "/b").with_body("2001:db8::7\n").create();
+        let valid = server.mock("GET", "/c").with_body("203.0.113.7\n").create();
+        let providers: Vec<String> = ["/a", "/b", "/c"].iter().map(|p| format!("{}{}", server.url(), p)).collect();
+
+        let ip = detect_ip::<Ipv4Addr>(&Client::new(), &providers, 1, PROVIDER_TIMEOUT, "IPv4").unwrap();
+        assert_eq!(ip, "203.0.113.7");
+        failing.assert();
+        wrong_family.assert();
+        valid.assert();
+    }
+
+    #[test]
+    fn is_global_ipv6_skips_local_scopes() {
+        assert!(is_global_ipv6(&"2a01:4f8::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&"::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&"fe80::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&"fd12:3456::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&"ff02::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,184 @@
+//! Announcing changed records: --event-pipe, --notify-url and --on-change.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+use log::warn;
+use reqwest::blocking::Client;
+use serde::Serialize;
+
+/// A record that was updated or created.
+#[derive(Serialize)]
+pub struct ChangeEvent<'a> {
+    pub fqdn: &'a str,
+    pub record_type: &'a str,
+    pub old_value: &'a str,
+    pub new_value: &'a str,
+    pub timestamp: u64,
+}
+
+/// Builds a command that runs `cmd` through the platform shell.
+pub fn shell_command(cmd: &str) -> Command {
+    if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.args(["/C", cmd]);
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.args(["-c", cmd]);
+        c
+    }
+}
+
+/// Writes one JSON line to the named pipe without ever blocking: if no reader
+/// is attached or the pipe buffer is full, the event is dropped. A regular
+/// file gets the line appended.
+#[cfg(unix)]
+fn emit_event(pipe: &Path, event: &ChangeEvent) {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut line = match serde_json::to_string(event) {
+        Ok(line) => line,
+        Err(e) => {
+            warn!("⚠️ Could not serialize change event: {}", e);
+            return;
+        }
+    };
+    line.push('\n');
+
+    let result = std::fs::OpenOptions::new()
+        .append(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(pipe)
+        .and_then(|mut f| f.write_all(line.as_bytes()));
+
+    match result {
+        Ok(()) => {}
+        // No reader attached (ENXIO) or pipe full (EAGAIN): drop the event.
+        Err(ref e) if e.raw_os_error() == Some(libc::ENXIO) || e.kind() == std::io::ErrorKind::WouldBlock => {}
+        Err(e) => warn!("⚠️ Could not write event to {}: {}", pipe.display(), e),
+    }
+}
+
+#[cfg(not(unix))]
+fn emit_event(pipe: &Path, _event: &ChangeEvent) {
+    warn!("⚠️ --event-pipe is only supported on Unix, ignoring {}", pipe.display());
+}
+
+/// How a changed record is announced: --event-pipe, --notify-url and
+/// --on-change. A failing hook is only a warning; the record did change.
+pub struct Hooks {
+    pub event_pipe: Option<PathBuf>,
+    pub notify_url: Option<String>,
+    pub on_change: Option<String>,
+    pub client: Client,
+}
+
+/// How long --notify-url may take to answer.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Hooks {
+    pub fn fire(&self, event: &ChangeEvent) {
+        if let Some(pipe) = &self.event_pipe {
+            emit_event(pipe, event);
+        }
+        if let Some(url) = &self.notify_url {
+            if let Err(e) = self.notify(url, event) {
+                warn!("⚠️ --notify-url failed for {} {}: {}", event.record_type, event.fqdn, e);
+            }
+        }
+        if let Some(cmd) = &self.on_change {
+            if let Err(e) = run_on_change(cmd, event) {
+                warn!("⚠️ --on-change failed for {} {}: {}", event.record_type, event.fqdn, e);
+            }
+        }
+    }
+
+    fn notify(&self, url: &str, event: &ChangeEvent) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.post(url)
+            .timeout(NOTIFY_TIMEOUT)
+            .json(event)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Runs the --on-change command with the event in DDNS_* variables.
+fn run_on_change(cmd: &str, event: &ChangeEvent) -> Result<(), Box<dyn std::error::Error>> {
+    let status = shell_command(cmd)
+        .env("DDNS_FQDN", event.fqdn)
+        .env("DDNS_RECORD_TYPE", event.record_type)
+        .env("DDNS_OLD_IP", event.old_value)
+        .env("DDNS_NEW_IP", event.new_value)
+        .env("DDNS_TIMESTAMP", event.timestamp.to_string())
+        .status()?;
+    if !status.success() {
+        return Err(format!("exited with {}", status).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> ChangeEvent<'static> {
+        ChangeEvent {
+            fqdn: "home.example.com",
+            record_type: "A",
+            old_value: "192.0.2.1",
+            new_value: "192.0.2.9",
+            timestamp: 100,
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn event_pipe_drops_events_without_a_reader() {
+        use std::io::Read;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let dir = std::env::temp_dir().join(format!("hetzner-ddns-pipe-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pipe = dir.join("events");
+        let path = std::ffi::CString::new(pipe.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+
+        // Would block forever if the open waited for a reader.
+        emit_event(&pipe, &event());
+
+        let mut reader = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&pipe)
+            .unwrap();
+        emit_event(&pipe, &event());
+        let mut received = String::new();
+        reader.read_to_string(&mut received).unwrap();
+        assert_eq!(received, format!("{}\n", serde_json::to_string(&event()).unwrap()));
+
+        // A regular file collects every event instead of overwriting the first.
+        let log = dir.join("events.log");
+        std::fs::write(&log, "").unwrap();
+        emit_event(&log, &event());
+        emit_event(&log, &event());
+        assert_eq!(std::fs::read_to_string(&log).unwrap().lines().count(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn on_change_gets_ddns_variables() {
+        let out = std::env::temp_dir().join(format!("hetzner-ddns-on-change-{}", std::process::id()));
+        let event = event();
+        let cmd = format!("echo \"$DDNS_FQDN $DDNS_RECORD_TYPE $DDNS_OLD_IP $DDNS_NEW_IP $DDNS_TIMESTAMP\" > {}", out.display());
+
+        run_on_change(&cmd, &event).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "home.example.com A 192.0.2.1 192.0.2.9 100\n");
+        assert!(run_on_change("exit 3", &event).is_err());
+        std::fs::remove_file(&out).unwrap();
+    }
+}
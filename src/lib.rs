@@ -0,0 +1,1129 @@
+//! Client for the Hetzner DNS API, plus the address detection, record
+//! syncing, state and reporting that the `hetzner-ddns` binary is built on.
+
+use std::cell::Cell;
+use std::net::IpAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, info, trace, warn};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{HeaderMap, AUTHORIZATION};
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub mod detect;
+pub mod hooks;
+pub mod metrics;
+pub mod state;
+pub mod sync;
+pub mod verify;
+
+/// A DNS zone of the account.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Zone {
+    pub id: String,
+    pub name: String,
+    /// The zone's authoritative nameservers.
+    #[serde(default)]
+    pub ns: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ZoneList {
+    zones: Vec<Zone>,
+    #[serde(default)]
+    meta: Option<Meta>,
+}
+
+#[derive(Deserialize)]
+struct Meta {
+    pagination: Option<Pagination>,
+}
+
+#[derive(Deserialize)]
+struct Pagination {
+    last_page: u32,
+}
+
+/// One page of a paginated list response.
+trait Page: serde::de::DeserializeOwned {
+    type Item;
+
+    /// The items on this page and the number of the last page.
+    fn into_items(self) -> (Vec<Self::Item>, u32);
+}
+
+fn last_page(meta: Option<Meta>) -> u32 {
+    meta.and_then(|m| m.pagination).map_or(1, |p| p.last_page)
+}
+
+impl Page for ZoneList {
+    type Item = Zone;
+
+    fn into_items(self) -> (Vec<Zone>, u32) {
+        (self.zones, last_page(self.meta))
+    }
+}
+
+/// A record as returned by the API. `name` is relative to the zone, with
+/// `@` for the apex.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Record {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    pub name: String,
+    pub value: String,
+    pub zone_id: String,
+    /// Absent when the record uses the zone's default TTL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct RecordList {
+    records: Vec<Record>,
+    #[serde(default)]
+    meta: Option<Meta>,
+}
+
+impl Page for RecordList {
+    type Item = Record;
+
+    fn into_items(self) -> (Vec<Record>, u32) {
+        (self.records, last_page(self.meta))
+    }
+}
+
+/// Body for creating a record. Unlike `Record` it has no `id`, which the API
+/// rejects on create.
+#[derive(Serialize, Debug)]
+pub struct NewRecord {
+    pub zone_id: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    pub name: String,
+    pub value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct BulkUpdate<'a> {
+    records: &'a [Record],
+}
+
+/// Outcome of a bulk update: which records were accepted and which failed.
+#[derive(Deserialize)]
+pub struct BulkUpdateResponse {
+    #[serde(default, alias = "valid_records")]
+    pub records: Vec<RecordRef>,
+    #[serde(default)]
+    pub failed_records: Vec<RecordRef>,
+}
+
+/// Just the id of a record; entries of `failed_records` may be incomplete.
+#[derive(Deserialize)]
+pub struct RecordRef {
+    #[serde(default)]
+    pub id: String,
+}
+
+#[derive(Deserialize)]
+struct RecordResponse {
+    record: Record,
+}
+
+#[derive(Deserialize)]
+struct ApiErrorResponse {
+    error: ApiError,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    message: String,
+    #[serde(default)]
+    code: Option<u16>,
+}
+
+/// A Hetzner DNS API request that came back with an error status.
+#[derive(Debug)]
+pub struct ApiRequestError {
+    action: String,
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiRequestError {
+    fn from_response(action: String, response: Response) -> Self {
+        let status = response.status();
+        let body = response.text().unwrap_or_default();
+        trace!("<- {} {}", status, body);
+        // Proxies may answer with HTML, so fall back to a snippet of the body.
+        let message = match serde_json::from_str::<ApiErrorResponse>(&body) {
+            Ok(e) => {
+                if let Some(code) = e.error.code {
+                    debug!("Hetzner API error code {}", code);
+                }
+                e.error.message
+            }
+            Err(_) => body.split_whitespace().collect::<Vec<_>>().join(" ").chars().take(200).collect(),
+        };
+        ApiRequestError { action, status, message }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// The token was rejected (401/403). Retrying won't help.
+    pub fn is_auth(&self) -> bool {
+        matches!(self.status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+    }
+
+    /// Rate limiting or a server error, which may go away on its own.
+    pub fn is_transient(&self) -> bool {
+        self.status == StatusCode::TOO_MANY_REQUESTS || self.status.is_server_error()
+    }
+}
+
+impl std::fmt::Display for ApiRequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = match self.status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => "the API token was rejected",
+            StatusCode::NOT_FOUND => "not found (was it deleted in the meantime?)",
+            StatusCode::UNPROCESSABLE_ENTITY => "the API rejected the request as invalid",
+            StatusCode::TOO_MANY_REQUESTS => "rate limited by the API",
+            s if s.is_server_error() => "the Hetzner API had a server error",
+            _ => "the API returned an error",
+        };
+        write!(f, "❌ {} failed: {}: {} ({})", self.action, reason, self.message, self.status)
+    }
+}
+
+impl std::error::Error for ApiRequestError {}
+
+/// How bad a failure is, from the least to the most severe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FailureKind {
+    Other,
+    /// Rate limiting, server and network errors, and failed detection, which
+    /// may go away on their own.
+    Transient,
+    /// The API token was rejected (401/403). Retrying won't help.
+    Auth,
+}
+
+/// Classifies the error a request or run failed with.
+pub fn failure_kind(e: &(dyn std::error::Error + 'static)) -> FailureKind {
+    if e.is::<detect::DetectionFailed>() {
+        return FailureKind::Transient;
+    }
+    if let Some(e) = e.downcast_ref::<ApiRequestError>() {
+        if e.is_auth() {
+            return FailureKind::Auth;
+        }
+        if e.is_transient() {
+            return FailureKind::Transient;
+        }
+    }
+    if e.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_connect() || e.is_timeout()) {
+        return FailureKind::Transient;
+    }
+    FailureKind::Other
+}
+
+/// Whether the API rejected the token. Every request uses the same token, so
+/// the remaining ones would fail too.
+pub fn is_auth_error(e: &(dyn std::error::Error + 'static)) -> bool {
+    failure_kind(e) == FailureKind::Auth
+}
+
+/// Returns the response if it succeeded, or the API error it carries.
+fn check_status(response: Response, action: impl FnOnce() -> String) -> Result<Response, ApiRequestError> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(ApiRequestError::from_response(action(), response))
+    }
+}
+
+/// Formats request headers for logging, with the API token replaced.
+fn redacted_headers(headers: &HeaderMap) -> String {
+    headers.iter()
+        .map(|(name, value)| {
+            let value = if name.as_str().eq_ignore_ascii_case("Auth-API-Token") || name == AUTHORIZATION {
+                "<redacted>"
+            } else {
+                value.to_str().unwrap_or("<binary>")
+            };
+            format!("{}: {}", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Parses the body of a successful response, logging it at trace level.
+fn read_json<T: DeserializeOwned>(response: Response) -> Result<T, Box<dyn std::error::Error>> {
+    let status = response.status();
+    let body = response.text()?;
+    trace!("<- {} {}", status, body);
+    Ok(serde_json::from_str(&body)?)
+}
+
+/// Counts requests made to the Hetzner DNS API during a run, so API usage can
+/// be checked against the rate limit. Every attempt counts, retries and
+/// failed requests included.
+#[derive(Clone, Copy, Default)]
+pub struct ApiUsage {
+    pub reads: u32,
+    pub writes: u32,
+}
+
+impl ApiUsage {
+    pub fn summary(&self) -> String {
+        let kind = if self.writes > 0 { "write" } else { "read-only" };
+        format!("📊 API usage: {} GET, {} PUT/POST ({} run)", self.reads, self.writes, kind)
+    }
+}
+
+/// Shared flag for shutting down, e.g. on SIGTERM. Retry backoffs and
+/// rate-limit waits end as soon as it is triggered.
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<(Mutex<bool>, Condvar)>);
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        let (stopped, wake) = &*self.0;
+        *stopped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        wake.notify_all();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sleeps for `duration`, or until shutdown is triggered. Returns false
+    /// if it was cut short.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let (stopped, wake) = &*self.0;
+        let stopped = stopped.lock().unwrap_or_else(|e| e.into_inner());
+        let (stopped, _) = wake.wait_timeout_while(stopped, duration, |stopped| !*stopped)
+            .unwrap_or_else(|e| e.into_inner());
+        !*stopped
+    }
+}
+
+/// Requests that may be sent back to back before the rate limit kicks in.
+const RATE_LIMIT_BURST: f64 = 10.0;
+
+/// Token bucket that caps the rate of requests to the Hetzner API. It starts
+/// full, so short runs aren't slowed down.
+struct RateLimiter {
+    per_second: f64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(per_second: f64) -> Self {
+        RateLimiter {
+            per_second,
+            bucket: Mutex::new((RATE_LIMIT_BURST, Instant::now())),
+        }
+    }
+
+    /// Blocks until a request may be sent, or until `shutdown` is triggered.
+    fn acquire(&self, shutdown: &Shutdown) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+                let (tokens, last) = *bucket;
+                let now = Instant::now();
+                let tokens = (tokens + now.duration_since(last).as_secs_f64() * self.per_second).min(RATE_LIMIT_BURST);
+                if tokens >= 1.0 {
+                    *bucket = (tokens - 1.0, now);
+                    return;
+                }
+                *bucket = (tokens, now);
+                Duration::from_secs_f64((1.0 - tokens) / self.per_second)
+            };
+            debug!("Rate limit reached, waiting {:.1}s", wait.as_secs_f64());
+            if !shutdown.sleep(wait) {
+                return;
+            }
+        }
+    }
+}
+
+/// Longest Retry-After the tool is willing to wait for.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Status codes retried by default: rate limiting and the server errors a
+/// gateway may return while the API is briefly unavailable.
+pub const DEFAULT_RETRY_ON: &[u16] = &[429, 500, 502, 503, 504];
+
+/// Retry policy for outbound HTTP requests: exponential backoff with jitter,
+/// starting at `base`. Gives up early once `shutdown` is triggered.
+#[derive(Clone)]
+pub struct Retry {
+    retries: u32,
+    base: Duration,
+    retry_on: Vec<u16>,
+    shutdown: Shutdown,
+}
+
+impl Retry {
+    pub fn new(retries: u32) -> Self {
+        Retry { retries, base: Duration::from_secs(2), retry_on: DEFAULT_RETRY_ON.to_vec(), shutdown: Shutdown::default() }
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Retries responses with these status codes instead of `DEFAULT_RETRY_ON`.
+    pub fn retry_on(mut self, statuses: &[u16]) -> Self {
+        self.retry_on = statuses.to_vec();
+        self
+    }
+
+    /// A policy that gives up after the first attempt.
+    pub fn none() -> Self {
+        Retry::new(0)
+    }
+
+    /// Backoff before retry number `attempt` (1-based), plus up to 50% jitter.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.base * 2u32.saturating_pow(attempt - 1);
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        delay + delay.mul_f64(f64::from(nanos % 1000) / 2000.0)
+    }
+
+    /// Sends a request, retrying network errors and responses with one of
+    /// the `retry_on` statuses. A 429 waits for Retry-After when the server
+    /// sends one. Other responses, including 401/403/422, come back right
+    /// away. Requests that aren't `idempotent` are only retried when they
+    /// can't have been processed: on connection errors and 429.
+    pub fn send(&self, action: &str, idempotent: bool, mut send: impl FnMut() -> reqwest::Result<Response>) -> reqwest::Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let result = send();
+            if attempt >= self.retries {
+                return result;
+            }
+            attempt += 1;
+            let (reason, wait) = match &result {
+                Err(e) if e.is_connect() || (idempotent && (e.is_timeout() || e.is_request())) => (e.to_string(), self.backoff(attempt)),
+                Ok(r) if !self.retry_on.contains(&r.status().as_u16()) => return result,
+                Ok(r) if r.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    (r.status().to_string(), retry_after(r).unwrap_or_else(|| self.backoff(attempt)))
+                }
+                Ok(r) if idempotent => (r.status().to_string(), self.backoff(attempt)),
+                _ => return result,
+            };
+            info!("🔁 {} failed ({}), retrying in {:.1}s ({}/{})", action, reason, wait.as_secs_f64(), attempt, self.retries);
+            if !self.shutdown.sleep(wait) {
+                return result;
+            }
+        }
+    }
+
+    /// Runs `f` until it succeeds or the retries are used up.
+    pub fn run<T, E: std::fmt::Display>(&self, action: &str, mut f: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    let wait = self.backoff(attempt);
+                    info!("🔁 {} failed ({}), retrying in {:.1}s ({}/{})", action, e, wait.as_secs_f64(), attempt, self.retries);
+                    if !self.shutdown.sleep(wait) {
+                        return Err(e);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Parses a Retry-After header given in seconds.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let secs: u64 = response.headers().get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(secs).min(MAX_RETRY_AFTER))
+}
+
+/// Base URL of the Hetzner DNS API.
+pub const DNS_API_URL: &str = "https://dns.hetzner.com/api/v1";
+
+/// User-Agent sent with every request, so the tool can be told apart in
+/// API logs.
+pub const USER_AGENT: &str = concat!("hetzner-ddns/", env!("CARGO_PKG_VERSION"));
+
+/// Entries requested per page from list endpoints (the API maximum).
+const PER_PAGE: u32 = 100;
+
+/// Client for the Hetzner DNS API. Requests are rate limited, retried on
+/// transient failures and counted.
+pub struct HetznerDnsClient {
+    client: Client,
+    base_url: String,
+    token: String,
+    usage: Cell<ApiUsage>,
+    limiter: RateLimiter,
+    retry: Retry,
+}
+
+/// What `ensure_record` did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnsureOutcome {
+    /// The record already held the address.
+    Unchanged,
+    /// The record was updated away from `old_value`.
+    Updated { old_value: String },
+}
+
+/// Record types `ensure_record` manages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+}
+
+impl RecordType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RecordType::A => "A",
+            RecordType::Aaaa => "AAAA",
+        }
+    }
+}
+
+impl HetznerDnsClient {
+    /// A client for the public API, allowing one request per second with
+    /// bursts of up to 10, and no retries.
+    pub fn new(token: impl Into<String>) -> Self {
+        HetznerDnsClient {
+            client: Client::builder()
+                .user_agent(USER_AGENT)
+                .build()
+                .unwrap_or_default(),
+            base_url: DNS_API_URL.to_string(),
+            token: token.into(),
+            usage: Cell::default(),
+            limiter: RateLimiter::new(1.0),
+            retry: Retry::none(),
+        }
+    }
+
+    /// Sends requests to `base_url` instead of the public API, e.g. a mock
+    /// server in tests.
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Uses an existing HTTP client, e.g. one with timeouts or a proxy.
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Switches to another API token, e.g. after the config was reloaded.
+    /// Returns whether it differs from the current one.
+    pub fn set_token(&mut self, token: impl Into<String>) -> bool {
+        let token = token.into();
+        let changed = token != self.token;
+        self.token = token;
+        changed
+    }
+
+    /// Caps the request rate; `per_second` must be positive.
+    pub fn with_rate_limit(mut self, per_second: f64) -> Self {
+        self.limiter = RateLimiter::new(per_second);
+        self
+    }
+
+    pub fn with_retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Requests made since the client was created or `reset_usage` was called.
+    pub fn usage(&self) -> ApiUsage {
+        self.usage.get()
+    }
+
+    pub fn reset_usage(&mut self) {
+        self.usage.set(ApiUsage::default());
+    }
+
+    /// Sends a request built by `request`, waiting for the rate limiter
+    /// before every attempt. The request is logged at trace level, with the
+    /// token redacted. Nothing is sent once shutdown was triggered.
+    fn send(&self, action: &str, idempotent: bool, request: impl Fn() -> RequestBuilder) -> Result<Response, Box<dyn std::error::Error>> {
+        if self.retry.shutdown.is_triggered() {
+            return Err(format!("❌ {} cancelled: shutting down", action).into());
+        }
+        let response = self.retry.send(action, idempotent, || {
+            self.limiter.acquire(&self.retry.shutdown);
+            let request = request().build()?;
+            if log::log_enabled!(log::Level::Trace) {
+                let body = request.body().and_then(|b| b.as_bytes()).map(String::from_utf8_lossy).unwrap_or_default();
+                trace!("-> {} {} [{}]{}{}", request.method(), request.url(), redacted_headers(request.headers()),
+                    if body.is_empty() { "" } else { " " }, body);
+            }
+            let mut usage = self.usage.get();
+            if request.method() == Method::GET {
+                usage.reads += 1;
+            } else {
+                usage.writes += 1;
+            }
+            self.usage.set(usage);
+            self.client.execute(request)
+        })?;
+        Ok(response)
+    }
+
+    /// Fetches every page of a list endpoint. `query` holds the filters
+    /// without the paging parameters.
+    fn get_all<P: Page>(&mut self, action: &str, path: &str, query: &[(&str, &str)]) -> Result<Vec<P::Item>, Box<dyn std::error::Error>> {
+        let mut items = Vec::new();
+        let mut page = 1;
+        loop {
+            let response = self.send(action, true, || {
+                self.client.get(format!("{}/{}", self.base_url, path))
+                    .header("Auth-API-Token", &self.token)
+                    .query(query)
+                    .query(&[("page", page), ("per_page", PER_PAGE)])
+            })?;
+            let list: P = read_json(check_status(response, || action.to_string())?)?;
+
+            let (mut found, last_page) = list.into_items();
+            items.append(&mut found);
+            if page >= last_page {
+                return Ok(items);
+            }
+            debug!("{}: fetched page {} of {}", action, page, last_page);
+            page += 1;
+        }
+    }
+
+    pub fn list_zones(&mut self) -> Result<Vec<Zone>, Box<dyn std::error::Error>> {
+        self.get_all::<ZoneList>("Listing zones", "zones", &[])
+    }
+
+    /// Looks up a zone by its exact name. The API answers 404 when there is
+    /// none.
+    fn zone_named(&mut self, name: &str) -> Result<Option<Zone>, Box<dyn std::error::Error>> {
+        match self.get_all::<ZoneList>("Looking up zone", "zones", &[("name", name)]) {
+            Ok(zones) => Ok(zones.into_iter().find(|z| normalize_fqdn(&z.name) == name)),
+            Err(e) if e.downcast_ref::<ApiRequestError>().is_some_and(|e| e.status == StatusCode::NOT_FOUND) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Finds the zones the given hostnames live in. For a single hostname
+    /// each parent domain is looked up by name, longest first, which saves
+    /// paging through large accounts; if none matches, or there are several
+    /// hostnames, every zone is listed.
+    pub fn zones_for(&mut self, fqdns: &[&str]) -> Result<Vec<Zone>, Box<dyn std::error::Error>> {
+        let [fqdn] = fqdns else {
+            return self.list_zones();
+        };
+        let mut candidate = *fqdn;
+        loop {
+            if let Some(zone) = self.zone_named(candidate)? {
+                return Ok(vec![zone]);
+            }
+            match candidate.split_once('.') {
+                Some((_, parent)) if parent.contains('.') => candidate = parent,
+                _ => break,
+            }
+        }
+        debug!("No zone found by name for {}, listing all zones", fqdn);
+        self.list_zones()
+    }
+
+    pub fn list_records(&mut self, zone_id: &str) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
+        self.get_all::<RecordList>(&format!("Listing records of zone {}", zone_id), "records", &[("zone_id", zone_id)])
+    }
+
+    pub fn update_record(&mut self, record: &Record) -> Result<(), Box<dyn std::error::Error>> {
+        let response = self.send("Updating record", true, || {
+            self.client.put(format!("{}/records/{}", self.base_url, record.id))
+                .header("Auth-API-Token", &self.token)
+                .header("Content-Type", "application/json")
+                .json(record)
+        })?;
+        check_status(response, || format!("Updating {} record {}", record.record_type, record.name))?;
+        Ok(())
+    }
+
+    /// Updates several records in one request.
+    pub fn update_records(&mut self, records: &[Record]) -> Result<BulkUpdateResponse, Box<dyn std::error::Error>> {
+        let response = self.send("Updating records", true, || {
+            self.client.put(format!("{}/records/bulk", self.base_url))
+                .header("Auth-API-Token", &self.token)
+                .header("Content-Type", "application/json")
+                .json(&BulkUpdate { records })
+        })?;
+        let response = check_status(response, || format!("Updating {} records", records.len()))?;
+        read_json(response)
+    }
+
+    pub fn create_record(&mut self, record: &NewRecord) -> Result<Record, Box<dyn std::error::Error>> {
+        // Not idempotent: a retried POST could create the record twice.
+        let response = self.send("Creating record", false, || {
+            self.client.post(format!("{}/records", self.base_url))
+                .header("Auth-API-Token", &self.token)
+                .header("Content-Type", "application/json")
+                .json(record)
+        })?;
+
+        let response = check_status(response, || format!("Creating {} record {}", record.record_type, record.name))?;
+        let created: RecordResponse = read_json(response)?;
+        Ok(created.record)
+    }
+
+    pub fn delete_record(&mut self, record: &Record) -> Result<(), Box<dyn std::error::Error>> {
+        let response = self.send("Deleting record", true, || {
+            self.client.delete(format!("{}/records/{}", self.base_url, record.id))
+                .header("Auth-API-Token", &self.token)
+        })?;
+        check_status(response, || format!("Deleting {} record {}", record.record_type, record.name))?;
+        Ok(())
+    }
+
+    /// Points the A or AAAA record of `fqdn` at `ip`, updating it only if it
+    /// holds a different address. The record must already exist.
+    pub fn ensure_record(&mut self, fqdn: &str, ip: IpAddr, record_type: RecordType) -> Result<EnsureOutcome, Box<dyn std::error::Error>> {
+        let record_type = record_type.as_str();
+        let expected = if ip.is_ipv4() { "A" } else { "AAAA" };
+        if record_type != expected {
+            return Err(format!("❌ {} is not an address for a {} record", ip, record_type).into());
+        }
+
+        let fqdn = normalize_fqdn(fqdn);
+        let zones = self.zones_for(&[&fqdn])?;
+        let (name, zone) = split_fqdn(&fqdn, &zones)?;
+        let records = self.list_records(&zone.id)?;
+        let record = find_record(&records, &name, record_type, &zone.id)
+            .ok_or_else(|| format!("❌ {} record {} not found", record_type, fqdn))?;
+
+        let mut changes = sync::Changes::default();
+        sync::plan_record(&mut changes, record, &ip.to_string(), None, Compare::ValueOnly, false, &fqdn);
+        let updates = std::mem::take(&mut changes.updates);
+        sync::apply_updates(self, updates, &mut changes.report)?;
+        // The record was either found current or the update was sent.
+        match changes.report.pop() {
+            Some(entry) if entry.action == sync::RecordAction::Updated => Ok(EnsureOutcome::Updated { old_value: record.value.clone() }),
+            Some(sync::RecordReport { error: Some(e), .. }) => Err(e.into()),
+            _ => Ok(EnsureOutcome::Unchanged),
+        }
+    }
+}
+
+/// Which record fields decide whether an update is needed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compare {
+    /// Only a different value triggers an update (the default).
+    ValueOnly,
+    /// A TTL other than the configured one also triggers an update.
+    ValueAndTtl,
+}
+
+/// Decides whether `record` has to be rewritten to hold `value` with `ttl`.
+/// Without a configured TTL only the value counts.
+pub fn needs_update(record: &Record, value: &str, ttl: Option<u32>, compare: Compare) -> bool {
+    match (compare, ttl) {
+        (Compare::ValueAndTtl, Some(ttl)) => record.value != value || record.ttl != Some(ttl),
+        _ => record.value != value,
+    }
+}
+
+/// Returns true if the record belongs to the zone it was fetched for. Records
+/// from another zone are logged and must never be updated.
+pub fn in_zone(record: &Record, zone_id: &str) -> bool {
+    if record.zone_id == zone_id {
+        return true;
+    }
+    warn!("⚠️ Skipping {} record {}: it belongs to zone {}, not {}", record.record_type, record.name, record.zone_id, zone_id);
+    false
+}
+
+/// Finds the record with the given name and type in the resolved zone.
+pub fn find_record<'a>(records: &'a [Record], name: &str, record_type: &str, zone_id: &str) -> Option<&'a Record> {
+    records.iter()
+        .filter(|r| r.name == name && r.record_type == record_type)
+        .find(|r| in_zone(r, zone_id))
+}
+
+/// Lowercases a domain name and drops the trailing root dot, as well as an
+/// explicit `@.` apex label.
+pub fn normalize_fqdn(name: &str) -> String {
+    let name = name.trim().trim_end_matches('.').to_ascii_lowercase();
+    match name.strip_prefix("@.") {
+        Some(rest) => rest.to_string(),
+        None => name,
+    }
+}
+
+/// Finds the zone that is the longest suffix of `fqdn` and returns the record
+/// name formed by the labels in front of it, e.g. `home.dyn` for
+/// `home.dyn.example.com` in zone `example.com`. The zone apex, written as
+/// `example.com` or `@.example.com`, is the record `@`.
+pub fn split_fqdn<'a>(fqdn: &str, zones: &'a [Zone]) -> Result<(String, &'a Zone), String> {
+    let fqdn = normalize_fqdn(fqdn);
+
+    zones.iter()
+        .filter_map(|zone| {
+            let zone_name = normalize_fqdn(&zone.name);
+            if fqdn == zone_name {
+                return Some(("@".to_string(), zone, zone_name.len()));
+            }
+            let record_name = fqdn.strip_suffix(&zone_name)?.strip_suffix('.')?;
+            (!record_name.is_empty()).then(|| (record_name.to_string(), zone, zone_name.len()))
+        })
+        .max_by_key(|(_, _, len)| *len)
+        .map(|(record_name, zone, _)| (record_name, zone))
+        .ok_or_else(|| format!("❌ No zone in this account matches {} or any of its parent domains", fqdn))
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Builds the FQDN of a record name within a zone; "@" is the zone apex.
+pub fn record_fqdn(name: &str, zone_name: &str) -> String {
+    if name == "@" {
+        zone_name.to_string()
+    } else {
+        format!("{}.{}", name, zone_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str, name: &str, record_type: &str, zone_id: &str) -> Record {
+        Record {
+            id: id.to_string(),
+            record_type: record_type.to_string(),
+            name: name.to_string(),
+            value: "192.0.2.1".to_string(),
+            zone_id: zone_id.to_string(),
+            ttl: Some(60),
+        }
+    }
+
+    pub(crate) fn api(base_url: String) -> HetznerDnsClient {
+        HetznerDnsClient::new("token").with_base_url(base_url).with_rate_limit(1000.0)
+    }
+
+    fn page_query(page: &str) -> mockito::Matcher {
+        mockito::Matcher::AllOf(vec![
+            mockito::Matcher::UrlEncoded("page".into(), page.into()),
+            mockito::Matcher::UrlEncoded("per_page".into(), PER_PAGE.to_string()),
+        ])
+    }
+
+    #[test]
+    fn retry_on_picks_the_retried_statuses() {
+        let mut server = mockito::Server::new();
+        let put = server.mock("PUT", "/records/1")
+            .with_status(502)
+            .expect(2)
+            .create();
+        let quick = Retry { base: Duration::ZERO, ..Retry::new(1) };
+        let r = record("1", "home", "A", "z1");
+
+        assert!(api(server.url()).with_retry(quick.clone()).update_record(&r).is_err());
+        put.assert();
+
+        let put = server.mock("PUT", "/records/1")
+            .with_status(502)
+            .expect(1)
+            .create();
+        assert!(api(server.url()).with_retry(quick.retry_on(&[429, 503])).update_record(&r).is_err());
+        put.assert();
+    }
+
+    #[test]
+    fn usage_counts_every_attempt() {
+        let mut server = mockito::Server::new();
+        // The lookup by name and the listing after it both get a 404.
+        let zones = server.mock("GET", "/zones")
+            .match_query(mockito::Matcher::Any)
+            .with_status(404)
+            .expect(2)
+            .create();
+        let put = server.mock("PUT", "/records/1")
+            .with_status(502)
+            .expect(2)
+            .create();
+        let mut api = api(server.url()).with_retry(Retry { base: Duration::ZERO, ..Retry::new(1) });
+
+        assert!(api.zones_for(&["example.com"]).is_err());
+        assert!(api.update_record(&record("1", "home", "A", "z1")).is_err());
+        zones.assert();
+        put.assert();
+        assert_eq!((api.usage().reads, api.usage().writes), (2, 2));
+    }
+
+    #[test]
+    fn shutdown_cuts_retries_short() {
+        let shutdown = Shutdown::default();
+        let retry = Retry::new(3).with_shutdown(shutdown.clone());
+        let started = Instant::now();
+        let mut attempts = 0;
+        let result: Result<(), &str> = retry.run("Testing", || {
+            attempts += 1;
+            shutdown.trigger();
+            Err("failed")
+        });
+        assert_eq!(result, Err("failed"));
+        assert_eq!(attempts, 1);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Once triggered nothing more is sent.
+        let mut server = mockito::Server::new();
+        let put = server.mock("PUT", "/records/1").expect(0).create();
+        let err = api(server.url()).with_retry(retry).update_record(&record("1", "home", "A", "z1")).unwrap_err();
+        assert_eq!(err.to_string(), "❌ Updating record cancelled: shutting down");
+        put.assert();
+    }
+
+    #[test]
+    fn shutdown_wakes_a_sleep() {
+        let shutdown = Shutdown::default();
+        assert!(shutdown.sleep(Duration::from_millis(1)));
+
+        let trigger = {
+            let shutdown = shutdown.clone();
+            std::thread::spawn(move || shutdown.trigger())
+        };
+        assert!(!shutdown.sleep(Duration::from_secs(30)));
+        trigger.join().unwrap();
+    }
+
+    #[test]
+    fn redacted_headers_hide_the_token() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Auth-API-Token", "secret".parse().unwrap());
+        headers.insert("content-type", "application/json".parse().unwrap());
+
+        let logged = redacted_headers(&headers);
+        assert!(!logged.contains("secret"));
+        assert!(logged.contains("auth-api-token: <redacted>"));
+        assert!(logged.contains("content-type: application/json"));
+    }
+
+    #[test]
+    fn zones_fetches_every_page() {
+        let mut server = mockito::Server::new();
+        let page1 = server.mock("GET", "/zones")
+            .match_query(page_query("1"))
+            .match_header("Auth-API-Token", "token")
+            .with_body(r#"{"zones":[{"id":"1","name":"example.com"}],"meta":{"pagination":{"page":1,"per_page":1,"last_page":2,"total_entries":2}}}"#)
+            .create();
+        let page2 = server.mock("GET", "/zones")
+            .match_query(page_query("2"))
+            .with_body(r#"{"zones":[{"id":"2","name":"other.de"}],"meta":{"pagination":{"page":2,"per_page":1,"last_page":2,"total_entries":2}}}"#)
+            .create();
+
+        let mut api = api(server.url());
+        let zones = api.list_zones().unwrap();
+
+        page1.assert();
+        page2.assert();
+        let names: Vec<&str> = zones.iter().map(|z| z.name.as_str()).collect();
+        assert_eq!(names, ["example.com", "other.de"]);
+        assert_eq!(api.usage().reads, 2);
+    }
+
+    #[test]
+    fn records_fetches_every_page() {
+        let mut server = mockito::Server::new();
+        let record = |id: &str| format!(r#"{{"id":"{}","type":"A","name":"host{}","value":"192.0.2.1","zone_id":"z1"}}"#, id, id);
+        let page1 = server.mock("GET", "/records")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("zone_id".into(), "z1".into()),
+                page_query("1"),
+            ]))
+            .with_body(format!(r#"{{"records":[{}],"meta":{{"pagination":{{"last_page":2}}}}}}"#, record("1")))
+            .create();
+        let page2 = server.mock("GET", "/records")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("zone_id".into(), "z1".into()),
+                page_query("2"),
+            ]))
+            .with_body(format!(r#"{{"records":[{}],"meta":{{"pagination":{{"last_page":2}}}}}}"#, record("2")))
+            .create();
+
+        let records = api(server.url()).list_records("z1").unwrap();
+
+        page1.assert();
+        page2.assert();
+        let ids: Vec<&str> = records.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, ["1", "2"]);
+    }
+
+    #[test]
+    fn zones_for_looks_up_parent_domains_by_name() {
+        let mut server = mockito::Server::new();
+        let miss = server.mock("GET", "/zones")
+            .match_query(mockito::Matcher::UrlEncoded("name".into(), "home.example.com".into()))
+            .with_status(404)
+            .with_body(r#"{"error":{"message":"zone not found","code":404}}"#)
+            .create();
+        let hit = server.mock("GET", "/zones")
+            .match_query(mockito::Matcher::UrlEncoded("name".into(), "example.com".into()))
+            .with_body(r#"{"zones":[{"id":"1","name":"example.com"}]}"#)
+            .create();
+
+        let zones = api(server.url()).zones_for(&["home.example.com"]).unwrap();
+
+        miss.assert();
+        hit.assert();
+        assert_eq!(zones.len(), 1);
+        assert_eq!(zones[0].id, "1");
+    }
+
+    #[test]
+    fn zones_for_falls_back_to_listing_all_zones() {
+        let mut server = mockito::Server::new();
+        let filtered = server.mock("GET", "/zones")
+            .match_query(mockito::Matcher::Regex("name=".into()))
+            .with_body(r#"{"zones":[]}"#)
+            .expect(2)
+            .create();
+        let all = server.mock("GET", "/zones")
+            .match_query(page_query("1"))
+            .with_body(r#"{"zones":[{"id":"1","name":"Example.com"}]}"#)
+            .create();
+
+        let zones = api(server.url()).zones_for(&["home.example.com"]).unwrap();
+
+        filtered.assert();
+        all.assert();
+        assert_eq!(zones[0].name, "Example.com");
+    }
+
+    #[test]
+    fn needs_update_ignores_ttl_by_default() {
+        let mut r = record("1", "home", "A", "zone-a");
+        r.ttl = Some(300);
+
+        assert!(!needs_update(&r, "192.0.2.1", Some(60), Compare::ValueOnly));
+        assert!(needs_update(&r, "192.0.2.2", Some(60), Compare::ValueOnly));
+    }
+
+    #[test]
+    fn needs_update_considers_ttl_when_asked() {
+        let mut r = record("1", "home", "A", "zone-a");
+        assert!(!needs_update(&r, "192.0.2.1", Some(60), Compare::ValueAndTtl));
+
+        r.ttl = Some(300);
+        assert!(needs_update(&r, "192.0.2.1", Some(60), Compare::ValueAndTtl));
+
+        r.ttl = None;
+        assert!(needs_update(&r, "192.0.2.1", Some(60), Compare::ValueAndTtl));
+        assert!(needs_update(&r, "192.0.2.2", Some(60), Compare::ValueAndTtl));
+    }
+
+    #[test]
+    fn needs_update_without_configured_ttl_compares_value_only() {
+        let mut r = record("1", "home", "A", "zone-a");
+        r.ttl = Some(300);
+
+        assert!(!needs_update(&r, "192.0.2.1", None, Compare::ValueAndTtl));
+        assert!(needs_update(&r, "192.0.2.2", None, Compare::ValueAndTtl));
+    }
+
+    fn zone(id: &str, name: &str) -> Zone {
+        Zone { id: id.to_string(), name: name.to_string(), ns: Vec::new() }
+    }
+
+    #[test]
+    fn split_fqdn_uses_zone_suffix() {
+        let zones = vec![zone("1", "example.com"), zone("2", "other.de")];
+
+        let (record_name, found) = split_fqdn("host.example.com", &zones).unwrap();
+        assert_eq!(record_name, "host");
+        assert_eq!(found.id, "1");
+    }
+
+    #[test]
+    fn split_fqdn_prefers_longest_zone_suffix() {
+        let zones = vec![
+            zone("1", "co.uk"),
+            zone("2", "example.co.uk"),
+            zone("3", "c.example.co.uk.other"),
+        ];
+
+        let (record_name, found) = split_fqdn("a.b.c.example.co.uk.", &zones).unwrap();
+        assert_eq!(record_name, "a.b.c");
+        assert_eq!(found.id, "2");
+    }
+
+    #[test]
+    fn split_fqdn_maps_zone_apex_to_at() {
+        let zones = vec![zone("1", "com"), zone("2", "example.com")];
+
+        let (record_name, found) = split_fqdn("example.com", &zones).unwrap();
+        assert_eq!(record_name, "@");
+        assert_eq!(found.id, "2");
+
+        let (record_name, found) = split_fqdn("@.example.com", &zones).unwrap();
+        assert_eq!(record_name, "@");
+        assert_eq!(found.id, "2");
+    }
+
+    #[test]
+    fn split_fqdn_requires_whole_labels() {
+        let zones = vec![zone("1", "ample.com")];
+
+        assert!(split_fqdn("host.example.com", &zones).is_err());
+    }
+
+    #[test]
+    fn split_fqdn_errors_when_no_zone_matches() {
+        let zones = vec![zone("1", "example.com")];
+
+        let err = split_fqdn("home.example.org", &zones).unwrap_err();
+        assert!(err.contains("home.example.org"));
+    }
+
+    #[test]
+    fn find_record_matches_name_type_and_zone() {
+        let records = vec![
+            record("1", "home", "AAAA", "zone-a"),
+            record("2", "home", "A", "zone-a"),
+        ];
+
+        let found = find_record(&records, "home", "A", "zone-a").unwrap();
+        assert_eq!(found.id, "2");
+    }
+
+    #[test]
+    fn find_record_rejects_record_from_other_zone() {
+        let records = vec![record("1", "home", "A", "zone-b")];
+
+        assert!(find_record(&records, "home", "A", "zone-a").is_none());
+    }
+
+    #[test]
+    fn find_record_skips_foreign_record_before_match() {
+        let records = vec![
+            record("1", "home", "A", "zone-b"),
+            record("2", "home", "A", "zone-a"),
+        ];
+
+        let found = find_record(&records, "home", "A", "zone-a").unwrap();
+        assert_eq!(found.id, "2");
+    }
+}
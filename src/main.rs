@@ -1,147 +1,2655 @@
 use std::env;
+use hetzner_ddns::detect::{
+    detect_ipv4, detect_ipv4_upnp, detect_ipv6, interface_addresses, is_public_ipv4, is_public_ipv6, Providers,
+    DEFAULT_IPV4_PROVIDERS, DEFAULT_IPV6_PROVIDERS, PROVIDER_TIMEOUT,
+};
+use hetzner_ddns::hooks::{shell_command, ChangeEvent, Hooks};
+use hetzner_ddns::metrics::Metrics;
+use hetzner_ddns::state::State;
+use hetzner_ddns::sync::{
+    apply_updates, create_record, format_ttl, plan_record, prune_aaaa, Changes, RecordAction, RecordReport, ValueSource,
+};
+use hetzner_ddns::verify::{verify_changes, zone_is_signed};
+use hetzner_ddns::{
+    failure_kind, find_record, in_zone, is_auth_error, normalize_fqdn, record_fqdn, split_fqdn, unix_now, ApiRequestError,
+    Compare, FailureKind, HetznerDnsClient, NewRecord, Record, Retry, Shutdown, Zone, DEFAULT_RETRY_ON, USER_AGENT,
+};
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
 use reqwest::blocking::Client;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use dotenv::dotenv;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use log::{debug, error, info, warn, LevelFilter};
+use regex::Regex;
 use dotenv::Error as DotenvError;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Exit code when the API rejects the token (401/403). Retrying won't help.
+const EXIT_AUTH: i32 = 3;
+/// Exit code for failures that may go away on their own: rate limiting,
+/// server errors and network problems.
+const EXIT_TRANSIENT: i32 = 4;
+/// Exit code of `status` when a record doesn't hold the detected address.
+const EXIT_DRIFT: i32 = 5;
+
+/// Maps a failed run to the process exit code.
+fn exit_code(e: &(dyn std::error::Error + 'static)) -> i32 {
+    if let Some(e) = e.downcast_ref::<HostsFailed>() {
+        return e.code;
+    }
+    kind_code(failure_kind(e))
+}
+
+fn kind_code(kind: FailureKind) -> i32 {
+    match kind {
+        FailureKind::Auth => EXIT_AUTH,
+        FailureKind::Transient => EXIT_TRANSIENT,
+        FailureKind::Other => 1,
+    }
+}
+
+/// Formats an error for the log, with a hint for the API errors a flag or
+/// variable of this tool can fix.
+fn describe(e: &(dyn std::error::Error + 'static)) -> String {
+    let hint = match e.downcast_ref::<ApiRequestError>() {
+        Some(e) if e.is_auth() => Some("check HETZNER_API_TOKEN and its DNS permissions"),
+        Some(e) if e.status() == StatusCode::TOO_MANY_REQUESTS => Some("try again later or lower --rate-limit"),
+        _ => None,
+    };
+    match hint {
+        Some(hint) => format!("{} ({})", e, hint),
+        None => e.to_string(),
+    }
+}
+
+/// Hostnames that failed during a cycle, and the exit code of the worst
+/// failure: a rejected token beats a transient failure beats anything else.
+#[derive(Default, Debug)]
+struct Failures {
+    hosts: usize,
+    code: i32,
+}
+
+impl Failures {
+    /// Counts `hosts` hostnames that failed because of `e`.
+    fn add(&mut self, hosts: usize, e: &(dyn std::error::Error + 'static)) {
+        self.add_code(hosts, exit_code(e));
+    }
+
+    fn add_code(&mut self, hosts: usize, code: i32) {
+        let rank = |code| match code {
+            EXIT_AUTH => 3,
+            EXIT_TRANSIENT => 2,
+            0 => 0,
+            _ => 1,
+        };
+        self.hosts += hosts;
+        if rank(code) > rank(self.code) {
+            self.code = code;
+        }
+    }
+}
+
+/// Some hostnames of a run failed; the run exits with the code of the worst
+/// failure.
+#[derive(Debug)]
+struct HostsFailed {
+    failed: usize,
+    total: usize,
+    code: i32,
+}
+
+impl std::fmt::Display for HostsFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "❌ {} of {} hostnames failed", self.failed, self.total)
+    }
+}
+
+impl std::error::Error for HostsFailed {}
+
+#[derive(Deserialize)]
+struct CloudServerResponse {
+    server: CloudServer,
+}
 
 #[derive(Deserialize)]
-struct Zone {
-    id: String,
-    name: String,
+struct CloudServer {
+    public_net: CloudPublicNet,
+}
+
+#[derive(Deserialize)]
+struct CloudPublicNet {
+    ipv4: Option<CloudIpv4>,
+}
+
+#[derive(Deserialize)]
+struct CloudIpv4 {
+    ip: String,
+    dns_ptr: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ChangeDnsPtr<'a> {
+    ip: &'a str,
+    dns_ptr: &'a str,
+}
+
+/// What --output json prints at the end of a run.
+#[derive(Serialize, Default)]
+struct RunReport {
+    ipv4: Option<String>,
+    ipv6: Option<String>,
+    records: Vec<RecordReport>,
+    /// Why the run left the records alone: "guard_cmd" or "metered".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped: Option<&'static str>,
+    /// The error that failed the run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// How the result of a run is printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable log lines
+    Text,
+    /// A single JSON document on stdout; only errors are logged (to stderr)
+    Json,
+}
+
+fn parse_rate_limit(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!("must be a positive number of requests per second: {}", s)),
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "hetzner-ddns",
+    version,
+    about = "Dynamic DNS updater for Hetzner",
+    after_help = "Exit codes: 0 success, 1 error, 2 changes pending (--dry-run), 3 API token rejected, 4 transient failure (rate limit, server or network error), 5 drift found (status)"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Action>,
+
+    /// Update the AAAA (IPv6) record as well
+    #[arg(global = true, long)]
+    ipv6: bool,
+
+    /// Read the API token and hosts from this TOML file instead of .env/environment
+    #[arg(global = true, long, value_name = "PATH", conflicts_with_all = ["fqdn", "ipv6"])]
+    config: Option<PathBuf>,
+
+    /// Hostname to update; repeat for several (overrides DNS_FQDN, which may be comma-separated)
+    #[arg(global = true, long, value_name = "FQDN")]
+    fqdn: Vec<String>,
+
+    /// Keep running and check for IP changes every --interval seconds; SIGHUP re-reads --config
+    #[arg(global = true, long, conflicts_with = "explicit_value")]
+    daemon: bool,
+
+    /// Read zones and records but only print what would change; exits 2 if anything would
+    #[arg(global = true, long, conflicts_with = "daemon")]
+    dry_run: bool,
+
+    /// Seconds between checks in daemon mode
+    #[arg(global = true, long, value_name = "SECONDS", default_value_t = 300, value_parser = clap::value_parser!(u64).range(1..), requires = "daemon")]
+    interval: u64,
+
+    /// Testing only: instead of detecting the addresses, feed one line of this file ("IPV4 [IPV6]") to each daemon cycle
+    #[arg(global = true, long, value_name = "PATH", hide = true, requires = "daemon", conflicts_with_all = ["interface", "ipv4_address", "ipv6_address", "recheck_ip"])]
+    replay: Option<PathBuf>,
+
+    /// Serve GET /healthz on this address: 200 while a cycle succeeded within two intervals (at least 60s), 503 otherwise
+    #[arg(global = true, long, value_name = "ADDR:PORT", requires = "daemon")]
+    health_addr: Option<SocketAddr>,
+
+    /// Print more details: -v adds a summary of API requests and unchanged records, -vv every API request and response
+    #[arg(global = true, short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only print errors
+    #[arg(global = true, short, long)]
+    quiet: bool,
+
+    /// Run this command and use its output as the API token (e.g. "pass show hetzner/token")
+    #[arg(global = true, long, value_name = "COMMAND")]
+    api_token_cmd: Option<String>,
+
+    /// Read the API token from this file (overrides HETZNER_API_TOKEN_FILE and HETZNER_API_TOKEN)
+    #[arg(global = true, long, value_name = "PATH", conflicts_with = "api_token_cmd")]
+    token_file: Option<PathBuf>,
+
+    /// Seconds an HTTP request may take in total
+    #[arg(global = true, long, value_name = "SECONDS", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: u64,
+
+    /// Seconds to wait for an HTTP connection to be established
+    #[arg(global = true, long, value_name = "SECONDS", default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    connect_timeout: u64,
+
+    /// Send all HTTP requests through this proxy (HTTPS_PROXY/HTTP_PROXY and NO_PROXY are honored without it)
+    #[arg(global = true, long, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// Trust this extra root certificate (PEM or DER), e.g. of a TLS-intercepting proxy
+    #[arg(global = true, long, value_name = "PATH")]
+    ca_cert: Option<PathBuf>,
+
+    /// Run this command first and skip the run if it exits non-zero
+    #[arg(global = true, long, value_name = "COMMAND")]
+    guard_cmd: Option<String>,
+
+    /// How often to retry a failed request (network errors and --retry-on statuses), with backoff starting at 2s
+    #[arg(global = true, long, value_name = "N", default_value_t = 2)]
+    retries: u32,
+
+    /// HTTP status codes that are retried, e.g. the 502/503/504 of a gateway in front of the API
+    #[arg(
+        global = true,
+        long,
+        value_name = "CODE,...",
+        value_delimiter = ',',
+        default_values_t = DEFAULT_RETRY_ON.to_vec(),
+        value_parser = clap::value_parser!(u16).range(400..600)
+    )]
+    retry_on: Vec<u16>,
+
+    /// Maximum Hetzner DNS API requests per second (bursts of up to 10 are allowed)
+    #[arg(global = true, long, value_name = "REQ_PER_SEC", default_value = "1", value_parser = parse_rate_limit)]
+    rate_limit: f64,
+
+    /// Skip the run while NetworkManager reports a metered connection (Linux only)
+    #[arg(global = true, long)]
+    skip_on_metered: bool,
+
+    /// Send log output to syslog instead of stdout/stderr
+    #[arg(global = true, long)]
+    syslog: bool,
+
+    /// Write a JSON event line to this named pipe whenever a record changes
+    #[arg(global = true, long, value_name = "PATH")]
+    event_pipe: Option<PathBuf>,
+
+    /// POST a JSON event to this URL whenever a record is updated or created
+    #[arg(global = true, long, value_name = "URL")]
+    notify_url: Option<String>,
+
+    /// Run this shell command whenever a record is updated or created, with DDNS_FQDN, DDNS_RECORD_TYPE, DDNS_OLD_IP, DDNS_NEW_IP and DDNS_TIMESTAMP set
+    #[arg(global = true, long, value_name = "COMMAND")]
+    on_change: Option<String>,
+
+    /// Fail if the detected IPv4 address is not a public address
+    #[arg(global = true, long)]
+    require_public_ip: bool,
+
+    /// Use this IPv4 address instead of detecting it ("-" reads it from standard input)
+    #[arg(global = true, long, value_name = "ADDR", conflicts_with_all = ["explicit_value", "recheck_ip"])]
+    ipv4_address: Option<String>,
+
+    /// Use this IPv6 address instead of detecting it ("-" reads it from standard input)
+    #[arg(global = true, long, value_name = "ADDR", conflicts_with_all = ["explicit_value", "recheck_ip"])]
+    ipv6_address: Option<String>,
+
+    /// Accept private, loopback, link-local and documentation addresses for --ipv4-address/--ipv6-address
+    #[arg(global = true, long, conflicts_with = "require_public_ip")]
+    allow_private: bool,
+
+    /// Set the reverse DNS (PTR) of a Hetzner Cloud server's primary IPv4 to the FQDN (needs exactly one hostname)
+    #[arg(global = true, long, requires = "server_id")]
+    update_cloud_rdns: bool,
+
+    /// Hetzner Cloud server ID used with --update-cloud-rdns
+    #[arg(global = true, long, value_name = "ID")]
+    server_id: Option<u64>,
+
+    /// Write the zone's records as JSON to this file before updating
+    #[arg(global = true, long, value_name = "PATH")]
+    snapshot_before: Option<PathBuf>,
+
+    /// Write the zone's records as JSON to this file after updating
+    #[arg(global = true, long, value_name = "PATH")]
+    snapshot_after: Option<PathBuf>,
+
+    /// Print which records changed value or TTL during this run
+    #[arg(global = true, long)]
+    show_diff: bool,
+
+    /// Update every A record in the zone whose name matches this regex, and every
+    /// AAAA record in zones of a host with --ipv6 (or ipv6 = true in --config)
+    #[arg(global = true, long, value_name = "REGEX")]
+    name_pattern: Option<String>,
+
+    /// Read the record value from standard input instead of detecting the public IP
+    #[arg(global = true, long, group = "explicit_value", conflicts_with_all = ["ipv6", "name_pattern"])]
+    value_stdin: bool,
+
+    /// Read the record value from a file instead of detecting the public IP
+    #[arg(global = true, long, value_name = "PATH", group = "explicit_value", conflicts_with_all = ["ipv6", "name_pattern"])]
+    value_file: Option<PathBuf>,
+
+    /// Record type to set with --value-stdin or --value-file
+    #[arg(global = true, long, value_name = "TYPE", default_value = "A", value_parser = parse_record_type, requires = "explicit_value")]
+    record_type: String,
+
+    /// How to detect the public IPv4 address (IPv6 always uses HTTP)
+    #[arg(global = true, long, value_enum, default_value_t = DetectVia::Http)]
+    detect_via: DetectVia,
+
+    /// Take the addresses from this network interface instead of asking web services
+    #[arg(global = true, long, value_name = "NAME", conflicts_with = "explicit_value")]
+    interface: Option<String>,
+
+    /// IPv4 detection URLs to try in order (overrides IP_DETECT_URLS_V4)
+    #[arg(global = true, long, value_name = "URL,...", value_delimiter = ',')]
+    ip_detect_urls_v4: Vec<String>,
+
+    /// IPv6 detection URLs to try in order (overrides IP_DETECT_URLS_V6)
+    #[arg(global = true, long, value_name = "URL,...", value_delimiter = ',')]
+    ip_detect_urls_v6: Vec<String>,
+
+    /// Detect the public IPs again after updating and warn if they changed mid-run
+    #[arg(global = true, long, conflicts_with = "explicit_value")]
+    recheck_ip: bool,
+
+    /// Only update records whose value differs, ignoring TTL differences (default)
+    #[arg(global = true, long, conflicts_with = "update_ttl")]
+    compare_ignore_ttl: bool,
+
+    /// Also update records whose value is current but whose TTL differs from --ttl/DNS_TTL
+    #[arg(global = true, long)]
+    update_ttl: bool,
+
+    /// TTL in seconds to write (overrides DNS_TTL); by default records keep their TTL
+    #[arg(global = true, long, value_name = "SECONDS", value_parser = parse_ttl)]
+    ttl: Option<u32>,
+
+    /// Create the A (and AAAA with --ipv6) record if it doesn't exist yet
+    #[arg(global = true, long, conflicts_with = "name_pattern")]
+    create: bool,
+
+    /// Delete the AAAA record while no public IPv6 address is found, and create it again once there is one
+    #[arg(global = true, long)]
+    prune_missing_aaaa: bool,
+
+    /// Print a Terraform/OpenTofu import block for each processed record
+    #[arg(global = true, long)]
+    terraform_import: bool,
+
+    /// Output format: human-readable lines, or a JSON report of the run on stdout
+    #[arg(global = true, long, value_enum, default_value_t = OutputFormat::Text, conflicts_with = "terraform_import")]
+    output: OutputFormat,
+
+    /// Remember written values in this file and skip the API while they still match
+    /// (without PATH: $XDG_CACHE_HOME/hetzner-ddns/state.json)
+    #[arg(global = true, long, value_name = "PATH", num_args = 0..=1)]
+    state_file: Option<Option<PathBuf>>,
+
+    /// Check the records even if the state file says they are current
+    #[arg(global = true, long)]
+    no_cache: bool,
+
+    /// Rewrite the records even if they already hold the detected value (implies --no-cache)
+    #[arg(global = true, long, conflicts_with = "dry_run")]
+    force: bool,
+
+    /// After updating, poll the zone's authoritative nameservers until they serve the new A/AAAA values
+    #[arg(global = true, long, conflicts_with = "dry_run")]
+    verify: bool,
+
+    /// How long --verify waits for the nameservers before failing
+    #[arg(global = true, long, value_name = "SECONDS", default_value_t = 60, requires = "verify")]
+    verify_timeout: u64,
+
+    /// Write Prometheus metrics to this file after every run (for the node_exporter textfile collector)
+    #[arg(global = true, long, value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+
+    /// Don't ask before updating multiple records (required with --name-pattern when not on a terminal)
+    #[arg(global = true, short, long, visible_alias = "assume-yes")]
+    yes: bool,
+}
+
+#[derive(clap::Subcommand, Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    /// Bring the records up to date (the default)
+    Update,
+    /// Show the records next to the detected addresses without changing anything
+    Status,
+}
+
+impl Cli {
+    fn compare(&self) -> Compare {
+        if self.update_ttl { Compare::ValueAndTtl } else { Compare::ValueOnly }
+    }
+
+    /// Options that need the records every run, which a --state-file hit
+    /// would skip.
+    fn bypasses_cache(&self) -> bool {
+        self.update_cloud_rdns
+            || self.terraform_import
+            || self.verify
+            || self.snapshot_before.is_some()
+            || self.snapshot_after.is_some()
+            || self.show_diff
+            || self.recheck_ip
+    }
+}
+
+/// How the public IPv4 address is detected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum DetectVia {
+    /// Ask an external "what is my IP" web service
+    Http,
+    /// Ask the local router via UPnP IGD, falling back to HTTP
+    Upnp,
+}
+
+/// Settings read from `--config`, e.g.
+///
+/// ```toml
+/// api_token = "..."
+/// ipv4_providers = ["https://ipv4.icanhazip.com", "https://api.ipify.org"]
+/// ipv4_consensus = 2
+/// ipv6_providers = ["https://ipv6.icanhazip.com"]
+///
+/// [[hosts]]
+/// fqdn = "home.example.com"
+/// ipv6 = true
+/// ttl = 300
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    api_token: Option<String>,
+    /// Detection URLs to try in order, instead of IP_DETECT_URLS_V4/V6.
+    ipv4_providers: Option<Vec<String>>,
+    ipv6_providers: Option<Vec<String>>,
+    /// Providers that must report the same address before it is used (default 1).
+    ipv4_consensus: Option<usize>,
+    ipv6_consensus: Option<usize>,
+    hosts: Vec<HostConfig>,
+}
+
+/// One hostname to keep up to date, from a `[[hosts]]` table or from
+/// DNS_FQDN/--fqdn.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct HostConfig {
+    fqdn: String,
+    /// Update the AAAA record as well.
+    #[serde(default)]
+    ipv6: bool,
+    /// TTL to write; when unset the record keeps its current TTL.
+    ttl: Option<u32>,
+}
+
+fn parse_config(content: &str) -> Result<Config, String> {
+    toml::from_str(content).map_err(|e| e.to_string())
+}
+
+fn load_config(path: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("❌ Could not read config file {}: {}", path.display(), e))?;
+    let config = parse_config(&content)
+        .map_err(|e| format!("❌ Invalid config file {}: {}", path.display(), e.trim_end()))?;
+    Ok(config)
+}
+
+/// Settings resolved once at startup and shared by every cycle.
+struct Setup {
+    hosts: Vec<HostConfig>,
+    /// --ttl, or DNS_TTL without --config, for hosts without their own TTL.
+    ttl: Option<u32>,
+    cloud_token: Option<String>,
+    name_pattern: Option<Regex>,
+    providers: Providers,
+    retry: Retry,
+    /// --state-file; without it every run asks the API.
+    state_file: Option<PathBuf>,
+    /// --ipv4-address and --ipv6-address, which skip detection.
+    ipv4_address: Option<Ipv4Addr>,
+    ipv6_address: Option<Ipv6Addr>,
+    hooks: Hooks,
+    /// Triggered by SIGINT/SIGTERM in daemon mode.
+    shutdown: Shutdown,
+}
+
+impl Setup {
+    /// Whether any host wants its AAAA record kept up to date.
+    fn wants_ipv6(&self, args: &Cli) -> bool {
+        args.ipv6 || self.hosts.iter().any(|h| h.ipv6)
+    }
+}
+
+fn parse_record_type(s: &str) -> Result<String, String> {
+    let record_type = s.to_ascii_uppercase();
+    if record_type.is_empty() || !record_type.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("invalid record type: {}", s));
+    }
+    Ok(record_type)
+}
+
+/// Longest string a single TXT character-string can hold.
+const TXT_CHUNK_LEN: usize = 255;
+
+/// Upper bound for a record's RDATA.
+const MAX_RDATA_LEN: usize = 65535;
+
+/// Checks that `value` is acceptable content for a record of `record_type`
+/// and returns it in the form the API expects.
+fn prepare_value(record_type: &str, value: &str) -> Result<String, String> {
+    match record_type {
+        "A" => value.parse::<Ipv4Addr>().map(|ip| ip.to_string()).map_err(|_| format!("not a valid IPv4 address: {}", value)),
+        "AAAA" => value.parse::<Ipv6Addr>().map(|ip| ip.to_string()).map_err(|_| format!("not a valid IPv6 address: {}", value)),
+        _ if value.is_empty() => Err("value is empty".to_string()),
+        "TXT" => txt_value(value),
+        _ => Ok(value.to_string()),
+    }
+}
+
+/// Quotes a TXT value, splitting it into 255-byte character-strings. Values
+/// that are already quoted are passed through unchanged.
+fn txt_value(value: &str) -> Result<String, String> {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        return Ok(value.to_string());
+    }
+
+    let mut chunks = Vec::new();
+    let mut rest = value;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(TXT_CHUNK_LEN);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+
+    // Each character-string costs one length byte on the wire.
+    if value.len() + chunks.len() > MAX_RDATA_LEN {
+        return Err(format!("TXT value is {} bytes, more than fits in one record", value.len()));
+    }
+
+    Ok(chunks.iter()
+        .map(|c| format!("\"{}\"", c.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+fn read_value_stdin(record_type: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input)
+        .map_err(|e| format!("❌ Could not read value from stdin: {}", e))?;
+    let value = prepare_value(record_type, input.trim())
+        .map_err(|e| format!("❌ Invalid {} value from stdin: {}", record_type, e))?;
+    Ok(value)
+}
+
+/// Parses an address given with `flag` ("-" reads it from stdin). Unless
+/// `allow_private` is set it has to pass `is_public`.
+fn given_address<T>(flag: &str, value: &str, allow_private: bool, is_public: fn(&T) -> bool) -> Result<T, Box<dyn std::error::Error>>
+where
+    T: std::str::FromStr + std::fmt::Display,
+{
+    let mut input = String::new();
+    let value = if value == "-" {
+        std::io::stdin().read_to_string(&mut input)
+            .map_err(|e| format!("❌ Could not read {} from stdin: {}", flag, e))?;
+        input.trim()
+    } else {
+        value.trim()
+    };
+    let ip: T = value.parse()
+        .map_err(|_| format!("❌ {} is not a valid address: {:?}", flag, value))?;
+    if !allow_private && !is_public(&ip) {
+        return Err(format!(
+            "❌ {} {} is a private, loopback, link-local or documentation address (use --allow-private)",
+            flag, ip
+        ).into());
+    }
+    Ok(ip)
+}
+
+/// Reads the whole file as the record value, dropping only the trailing
+/// newline.
+fn read_value_file(path: &Path, record_type: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("❌ Could not read value from {}: {}", path.display(), e))?;
+    let value = content.strip_suffix('\n').unwrap_or(&content);
+    let value = value.strip_suffix('\r').unwrap_or(value);
+    let value = prepare_value(record_type, value)
+        .map_err(|e| format!("❌ Invalid {} value in {}: {}", record_type, path.display(), e))?;
+    Ok(value)
+}
+
+/// Parses a --replay file: one "IPV4 [IPV6]" line per cycle, skipping blank
+/// lines and `#` comments.
+fn parse_replay(content: &str) -> Result<Vec<ValueSource>, String> {
+    let mut entries = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let ip4 = fields.next().unwrap_or_default();
+        let ip6 = fields.next();
+        if ip4.parse::<Ipv4Addr>().is_err() || ip6.is_some_and(|ip| ip.parse::<Ipv6Addr>().is_err()) || fields.next().is_some() {
+            return Err(format!("line {}: expected an IPv4 address, optionally followed by an IPv6 address", n + 1));
+        }
+        entries.push(ValueSource::Detected { ip4: ip4.to_string(), ip6: ip6.map(str::to_string) });
+    }
+    Ok(entries)
+}
+
+fn read_replay(path: &Path) -> Result<Vec<ValueSource>, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("❌ Could not read --replay file {}: {}", path.display(), e))?;
+    let entries = parse_replay(&content)
+        .map_err(|e| format!("❌ Invalid --replay file {}: {}", path.display(), e))?;
+    Ok(entries)
+}
+
+/// Runs `cmd` through the shell and returns its trimmed stdout as the API
+/// token. Stderr is passed through so password manager prompts still work.
+fn token_from_command(cmd: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let output = shell_command(cmd)
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("❌ Could not run --api-token-cmd: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("❌ --api-token-cmd failed ({})", output.status).into());
+    }
+
+    let token = String::from_utf8(output.stdout)
+        .map_err(|_| "❌ --api-token-cmd printed a token that is not valid UTF-8")?
+        .trim()
+        .to_string();
+    if token.is_empty() {
+        return Err("❌ --api-token-cmd printed an empty token".into());
+    }
+    Ok(token)
+}
+
+/// Builds the HTTP client shared by IP detection, the Hetzner APIs and the
+/// hooks.
+fn build_client(args: &Cli) -> Result<Client, Box<dyn std::error::Error>> {
+    let mut builder = Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(Duration::from_secs(args.timeout))
+        .connect_timeout(Duration::from_secs(args.connect_timeout));
+
+    if let Some(url) = &args.proxy {
+        let proxy = reqwest::Proxy::all(url.as_str())
+            .map_err(|e| format!("❌ Invalid --proxy {}: {}", url, e))?
+            .no_proxy(reqwest::NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+
+    if let Some(path) = &args.ca_cert {
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("❌ Could not read --ca-cert {}: {}", path.display(), e))?;
+        let cert = reqwest::Certificate::from_pem(&bytes)
+            .or_else(|_| reqwest::Certificate::from_der(&bytes))
+            .map_err(|e| format!("❌ Invalid certificate in {}: {}", path.display(), e))?;
+        builder = builder.add_root_certificate(cert);
+    }
+
+    Ok(builder.build().map_err(|e| format!("❌ Could not set up the HTTP client: {}", e))?)
+}
+
+/// Name of the systemd credential (`LoadCredential=hetzner_api_token:...`)
+/// holding the API token.
+const TOKEN_CREDENTIAL: &str = "hetzner_api_token";
+
+/// Finds the API token. The first of these wins: --token-file or
+/// --api-token-cmd, the config file, HETZNER_API_TOKEN_FILE, the systemd
+/// credential in $CREDENTIALS_DIRECTORY, and HETZNER_API_TOKEN.
+fn find_api_token(args: &Cli, config_token: Option<String>) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(path) = &args.token_file {
+        return read_token_file(path);
+    }
+    if let Some(cmd) = &args.api_token_cmd {
+        return token_from_command(cmd);
+    }
+    if let Some(token) = config_token {
+        return Ok(token);
+    }
+    if let Some(path) = env::var_os("HETZNER_API_TOKEN_FILE").filter(|p| !p.is_empty()) {
+        return read_token_file(Path::new(&path));
+    }
+    if let Some(dir) = env::var_os("CREDENTIALS_DIRECTORY").filter(|d| !d.is_empty()) {
+        let path = Path::new(&dir).join(TOKEN_CREDENTIAL);
+        if path.exists() {
+            return read_token_file(&path);
+        }
+    }
+    env::var("HETZNER_API_TOKEN").map_err(|_| {
+        format!(
+            "❌ No API token found. Checked --token-file, --api-token-cmd, api_token in --config, \
+             HETZNER_API_TOKEN_FILE, $CREDENTIALS_DIRECTORY/{} and HETZNER_API_TOKEN (check .env file)",
+            TOKEN_CREDENTIAL
+        ).into()
+    })
+}
+
+/// Reads an API token file, dropping the trailing newline.
+fn read_token_file(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let token = std::fs::read_to_string(path)
+        .map_err(|e| format!("❌ Could not read API token from {}: {}", path.display(), e))?;
+    let token = token.trim_end();
+    if token.is_empty() {
+        return Err(format!("❌ API token file {} is empty", path.display()).into());
+    }
+    Ok(token.to_string())
+}
+
+/// Where a bare --state-file keeps its state: under $XDG_CACHE_HOME, or
+/// ~/.cache when that is unset.
+fn default_state_file() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let cache = match env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => env::var_os("HOME").filter(|d| !d.is_empty())
+            .map(|home| Path::new(&home).join(".cache"))
+            .ok_or("❌ --state-file without a path needs XDG_CACHE_HOME or HOME to be set")?,
+    };
+    Ok(cache.join("hetzner-ddns").join("state.json"))
+}
+
+/// The detection URLs and consensus from the flags, --config and the
+/// environment.
+fn resolve_providers(args: &Cli, config: Option<&Config>) -> Result<Providers, Box<dyn std::error::Error>> {
+    let (v4_consensus, v6_consensus) = config
+        .map_or((1, 1), |c| (c.ipv4_consensus.unwrap_or(1), c.ipv6_consensus.unwrap_or(1)));
+    if v4_consensus == 0 || v6_consensus == 0 {
+        return Err("❌ ipv4_consensus and ipv6_consensus must be at least 1".into());
+    }
+    Ok(Providers {
+        v4: Providers::resolve(&args.ip_detect_urls_v4, config.and_then(|c| c.ipv4_providers.clone()), "IP_DETECT_URLS_V4", DEFAULT_IPV4_PROVIDERS),
+        v6: Providers::resolve(&args.ip_detect_urls_v6, config.and_then(|c| c.ipv6_providers.clone()), "IP_DETECT_URLS_V6", DEFAULT_IPV6_PROVIDERS),
+        v4_consensus,
+        v6_consensus,
+        timeout: PROVIDER_TIMEOUT.min(Duration::from_secs(args.timeout)),
+    })
+}
+
+/// Makes sure every family `hosts` detect over HTTP has enough detection
+/// URLs for its consensus.
+fn check_providers(args: &Cli, providers: &Providers, setup: &Setup, hosts: &[HostConfig]) -> Result<(), Box<dyn std::error::Error>> {
+    // Addresses that aren't detected over HTTP need no providers.
+    let detects = !args.value_stdin && args.value_file.is_none() && args.interface.is_none() && args.replay.is_none();
+    if detects && setup.ipv4_address.is_none() {
+        if providers.v4.is_empty() {
+            return Err("❌ No IPv4 detection URLs configured (ipv4_providers, --ip-detect-urls-v4 or IP_DETECT_URLS_V4)".into());
+        }
+        if providers.v4.len() < providers.v4_consensus {
+            return Err(format!("❌ ipv4_consensus = {} needs at least as many IPv4 detection URLs, got {}", providers.v4_consensus, providers.v4.len()).into());
+        }
+    }
+    if detects && setup.ipv6_address.is_none() && (args.ipv6 || hosts.iter().any(|h| h.ipv6)) {
+        if providers.v6.is_empty() {
+            return Err("❌ No IPv6 detection URLs configured (ipv6_providers, --ip-detect-urls-v6 or IP_DETECT_URLS_V6)".into());
+        }
+        if providers.v6.len() < providers.v6_consensus {
+            return Err(format!("❌ ipv6_consensus = {} needs at least as many IPv6 detection URLs, got {}", providers.v6_consensus, providers.v6.len()).into());
+        }
+    }
+    Ok(())
+}
+
+/// Runs the guard command and returns whether updates may proceed: a
+/// non-zero exit status vetoes this run.
+fn guard_allows(cmd: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let status = shell_command(cmd)
+        .status()
+        .map_err(|e| format!("❌ Could not run --guard-cmd: {}", e))?;
+    if !status.success() {
+        info!("⏸️ Skipping this run: --guard-cmd exited with {}", status);
+    }
+    Ok(status.success())
+}
+
+/// Asks NetworkManager over D-Bus whether the primary connection is metered
+/// (NM_METERED_YES or NM_METERED_GUESS_YES).
+#[cfg(target_os = "linux")]
+fn connection_is_metered() -> Result<bool, Box<dyn std::error::Error>> {
+    let connection = zbus::blocking::Connection::system()?;
+    let proxy = zbus::blocking::Proxy::new(
+        &connection,
+        "org.freedesktop.NetworkManager",
+        "/org/freedesktop/NetworkManager",
+        "org.freedesktop.NetworkManager",
+    )?;
+    let metered: u32 = proxy.get_property("Metered")?;
+    Ok(matches!(metered, 1 | 3))
+}
+
+/// Returns whether this run should be skipped because of a metered
+/// connection. If NetworkManager can't be asked, the run goes ahead.
+#[cfg(target_os = "linux")]
+fn skip_for_metered() -> bool {
+    match connection_is_metered() {
+        Ok(true) => {
+            info!("⏸️ Skipping this run: the active connection is metered");
+            true
+        }
+        Ok(false) => false,
+        Err(e) => {
+            warn!("⚠️ Could not query NetworkManager for metered state: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn skip_for_metered() -> bool {
+    debug!("--skip-on-metered is only supported on Linux with NetworkManager");
+    false
+}
+
+/// Detects the public IPv4 with the chosen backend. UPnP falls back to HTTP
+/// when no gateway answers or the router doesn't support it.
+fn detect_ipv4_via(client: &Client, via: DetectVia, providers: &Providers, retry: &Retry) -> Result<String, Box<dyn std::error::Error>> {
+    if via == DetectVia::Upnp {
+        match detect_ipv4_upnp() {
+            Ok(ip) => {
+                debug!("Detected IPv4 {} via UPnP", ip);
+                return Ok(ip);
+            }
+            Err(e) => warn!("⚠️ UPnP detection failed ({}), falling back to HTTP", e),
+        }
+    }
+    detect_ipv4(client, providers, retry)
+}
+
+/// Detects the public IPs again at the end of a run and warns if they moved
+/// away from the addresses the run started with.
+fn recheck_ips(client: &Client, args: &Cli, setup: &Setup, ip4: &str, ip6: Option<&str>) {
+    let (now4, now6) = match detect_ips(client, args, setup) {
+        Ok(ips) => ips,
+        Err(e) => {
+            warn!("⚠️ Could not re-check public IPs: {}", e);
+            return;
+        }
+    };
+    if now4 != ip4 {
+        warn!("⚠️ Public IPv4 changed during the run ({} -> {}). Run again to update.", ip4, now4);
+    } else {
+        debug!("Public IPv4 unchanged since the start of the run.");
+    }
+
+    if setup.wants_ipv6(args) {
+        if now6.as_deref() != ip6 {
+            warn!("⚠️ Public IPv6 changed during the run ({} -> {}). Run again to update.",
+                ip6.unwrap_or("none"), now6.as_deref().unwrap_or("none"));
+        } else {
+            debug!("Public IPv6 unchanged since the start of the run.");
+        }
+    }
+}
+
+/// Detects the public IPv4 and, if available, IPv6 address, either from
+/// --interface or from the web providers.
+fn detect_ips(client: &Client, args: &Cli, setup: &Setup) -> Result<(String, Option<String>), Box<dyn std::error::Error>> {
+    let Some(name) = &args.interface else {
+        let ip4 = match setup.ipv4_address {
+            Some(ip) => ip.to_string(),
+            None => detect_ipv4_via(client, args.detect_via, &setup.providers, &setup.retry)?,
+        };
+        let ip6 = match setup.ipv6_address {
+            Some(ip) => Some(ip.to_string()),
+            None => {
+                // Hosts without IPv6 are common; don't wait for retries there.
+                let retry6 = if setup.wants_ipv6(args) { setup.retry.clone() } else { Retry::none() };
+                detect_ipv6(client, &setup.providers, &retry6)
+            }
+        };
+        return Ok((ip4, ip6));
+    };
+
+    let (ip4, ip6) = interface_addresses(name)?;
+    let ip4 = setup.ipv4_address.or(ip4)
+        .ok_or_else(|| format!("❌ Interface {} has no public IPv4 address", name))?;
+    let ip6 = setup.ipv6_address.or(ip6);
+    debug!("Using IPv4 {} for interface {}", ip4, name);
+    match ip6 {
+        Some(ip6) => debug!("Using IPv6 {} for interface {}", ip6, name),
+        // --prune-missing-aaaa treats the missing address as gone.
+        None if setup.wants_ipv6(args) && !args.prune_missing_aaaa => {
+            return Err(format!("❌ Interface {} has no global IPv6 address", name).into());
+        }
+        None => debug!("Interface {} has no global IPv6 address", name),
+    }
+    Ok((ip4.to_string(), ip6.map(|ip| ip.to_string())))
+}
+
+/// --update-cloud-rdns points the PTR at the only hostname, so there must be
+/// exactly one.
+fn check_rdns_hosts(args: &Cli, hosts: &[HostConfig]) -> Result<(), Box<dyn std::error::Error>> {
+    if args.update_cloud_rdns && hosts.len() != 1 {
+        return Err(format!("❌ --update-cloud-rdns needs exactly one hostname to point the PTR at, got {}", hosts.len()).into());
+    }
+    Ok(())
+}
+
+/// Points the PTR of the server's primary IPv4 at `fqdn` via the Hetzner Cloud
+/// API. This is a separate API from DNS and uses its own token (HCLOUD_TOKEN).
+fn update_cloud_rdns(client: &Client, retry: &Retry, cloud_token: &str, server_id: u64, fqdn: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let server: CloudServerResponse = retry.send("Reading cloud server", true, || {
+        client.get(format!("https://api.hetzner.cloud/v1/servers/{}", server_id))
+            .bearer_auth(cloud_token)
+            .send()
+    })?.error_for_status()?.json()?;
+
+    let ipv4 = server.server.public_net.ipv4
+        .ok_or("❌ Cloud server has no primary IPv4 address")?;
+
+    if ipv4.dns_ptr.as_deref() == Some(fqdn) {
+        debug!("✅ Cloud rDNS already up to date: {} -> {}", ipv4.ip, fqdn);
+        return Ok(());
+    }
+
+    info!("🔄 Updating cloud rDNS for {} to {}", ipv4.ip, fqdn);
+    // Setting the same PTR twice is harmless, so this may be retried.
+    retry.send("Updating cloud rDNS", true, || {
+        client.post(format!("https://api.hetzner.cloud/v1/servers/{}/actions/change_dns_ptr", server_id))
+            .bearer_auth(cloud_token)
+            .json(&ChangeDnsPtr { ip: &ipv4.ip, dns_ptr: fqdn })
+            .send()
+    })?.error_for_status()?;
+    info!("✅ Cloud rDNS updated.");
+
+    Ok(())
+}
+
+/// Lowest TTL Hetzner DNS accepts.
+const MIN_TTL: u32 = 60;
+/// Highest TTL that makes sense for a dynamic record (one day).
+const MAX_TTL: u32 = 86400;
+
+fn check_ttl(ttl: u32) -> Result<u32, String> {
+    if (MIN_TTL..=MAX_TTL).contains(&ttl) {
+        Ok(ttl)
+    } else {
+        Err(format!("TTL must be between {} and {} seconds: {}", MIN_TTL, MAX_TTL, ttl))
+    }
+}
+
+fn parse_ttl(s: &str) -> Result<u32, String> {
+    let ttl = s.trim().parse::<u32>().map_err(|_| format!("TTL must be a number of seconds: {}", s))?;
+    check_ttl(ttl)
+}
+
+/// Asks the user to confirm on the terminal. Without a terminal there is no
+/// one to ask, so the answer is no.
+fn confirm(question: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Ok(false);
+    }
+
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    stdin.read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Normalizes the configured hostnames, dropping blanks and duplicates, and
+/// gives hosts without a TTL of their own the `default_ttl`.
+fn normalize_hosts(raw: Vec<HostConfig>, default_ttl: Option<u32>) -> Result<Vec<HostConfig>, Box<dyn std::error::Error>> {
+    let mut hosts: Vec<HostConfig> = Vec::new();
+    for mut host in raw {
+        host.fqdn = normalize_fqdn(&host.fqdn);
+        if host.fqdn.is_empty() {
+            continue;
+        }
+        if !host.fqdn.contains('.') {
+            return Err(format!("❌ {} is not a valid FQDN (e.g. dyndns.example.com)", host.fqdn).into());
+        }
+        if let Some(ttl) = host.ttl {
+            check_ttl(ttl).map_err(|e| format!("❌ Invalid ttl for {}: {}", host.fqdn, e))?;
+        }
+        host.ttl = host.ttl.or(default_ttl);
+        if !hosts.iter().any(|h| h.fqdn == host.fqdn) {
+            hosts.push(host);
+        }
+    }
+    if hosts.is_empty() {
+        return Err("❌ DNS_FQDN must be a valid FQDN (e.g. dyndns.example.com)".into());
+    }
+    Ok(hosts)
+}
+
+/// Builds an import block for the hetznerdns Terraform provider, so the
+/// record can be brought under IaC management. The resource is named after
+/// the record's FQDN and type, so records from different zones don't clash.
+fn terraform_import_block(record: &Record, zone_name: &str) -> String {
+    let mut resource: String = format!("{}_{}", record_fqdn(&record.name, zone_name), record.record_type)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    // Terraform names can't start with a digit.
+    if resource.starts_with(|c: char| c.is_ascii_digit()) {
+        resource.insert_str(0, "record_");
+    }
+    format!("import {{\n  to = hetznerdns_record.{}\n  id = \"{}\"\n}}", resource, record.id)
+}
+
+fn write_snapshot(path: &Path, records: &[Record]) -> Result<(), Box<dyn std::error::Error>> {
+    let json = serde_json::to_string_pretty(records)?;
+    std::fs::write(path, json)
+        .map_err(|e| format!("❌ Could not write snapshot to {}: {}", path.display(), e))?;
+    info!("📸 Wrote snapshot of {} records to {}", records.len(), path.display());
+    Ok(())
+}
+
+/// The records a hostname should hold, as `(record type, value)`.
+fn wanted_values<'a>(args: &'a Cli, host: &HostConfig, source: &'a ValueSource) -> Vec<(&'a str, &'a str)> {
+    match source {
+        ValueSource::Explicit(value) => vec![(args.record_type.as_str(), value.as_str())],
+        ValueSource::Detected { ip4, ip6 } => {
+            let mut wanted = vec![("A", ip4.as_str())];
+            if let (true, Some(ip6)) = (host.ipv6, ip6) {
+                wanted.push(("AAAA", ip6.as_str()));
+            }
+            wanted
+        }
+    }
+}
+
+/// Describes, one line per record, what differs between two snapshots of a
+/// zone. Records are matched by id.
+fn diff_records(before: &[Record], after: &[Record]) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for old in before {
+        match after.iter().find(|r| r.id == old.id) {
+            Some(new) => {
+                let mut changes = Vec::new();
+                if old.value != new.value {
+                    changes.push(format!("value {} -> {}", old.value, new.value));
+                }
+                if old.ttl != new.ttl {
+                    changes.push(format!("ttl {} -> {}", format_ttl(old.ttl), format_ttl(new.ttl)));
+                }
+                if !changes.is_empty() {
+                    lines.push(format!("~ {} {}: {}", new.record_type, new.name, changes.join(", ")));
+                }
+            }
+            None => lines.push(format!("- {} {}: {}", old.record_type, old.name, old.value)),
+        }
+    }
+
+    for new in after.iter().filter(|r| !before.iter().any(|old| old.id == r.id)) {
+        lines.push(format!("+ {} {}: {}", new.record_type, new.name, new.value));
+    }
+
+    lines
+}
+
+/// The level for our own messages: info by default, errors only with
+/// `--quiet`, debug with `-v` and trace with `-vv`.
+fn log_level(verbose: u8, quiet: bool) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    }
+}
+
+/// Installs env_logger, or a syslog logger (facility daemon) when `--syslog`
+/// is given. Errors go to stderr and everything else to stdout, except with
+/// `--output json`, where stdout only carries the report. Dependencies only
+/// get through with warnings and errors. On a terminal lines are printed as
+/// plain messages; otherwise (e.g. under systemd) they carry a timestamp and
+/// level. RUST_LOG overrides the levels.
+fn init_logging(use_syslog: bool, json_output: bool, level: LevelFilter) -> Result<(), Box<dyn std::error::Error>> {
+    if use_syslog {
+        return init_syslog(level);
+    }
+
+    let logger = |target: env_logger::Target, terminal: bool| {
+        let mut builder = env_logger::Builder::new();
+        builder
+            .target(target)
+            .filter_level(LevelFilter::Warn)
+            .filter_module(module_path!(), level)
+            .parse_default_env();
+        if terminal {
+            builder.format(|buf, record| writeln!(buf, "{}", record.args()));
+        } else {
+            builder.format_target(false);
+        }
+        builder.build()
+    };
+    let stderr = logger(env_logger::Target::Stderr, std::io::stderr().is_terminal());
+    let max_level = stderr.filter();
+    if json_output {
+        log::set_boxed_logger(Box::new(stderr))?;
+    } else {
+        let stdout = logger(env_logger::Target::Stdout, std::io::stdout().is_terminal());
+        log::set_boxed_logger(Box::new(SplitLogger { stdout, stderr }))?;
+    }
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Writes errors to stderr and all other levels to stdout.
+struct SplitLogger {
+    stdout: env_logger::Logger,
+    stderr: env_logger::Logger,
+}
+
+impl log::Log for SplitLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.stdout.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if record.level() == log::Level::Error {
+            self.stderr.log(record);
+        } else {
+            self.stdout.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.stdout.flush();
+        self.stderr.flush();
+    }
+}
+
+/// Sends our own messages at `level`, and warnings and errors of other
+/// crates, to syslog. RUST_LOG overrides this like it does on the terminal.
+#[cfg(unix)]
+fn init_syslog(level: LevelFilter) -> Result<(), Box<dyn std::error::Error>> {
+    let formatter = syslog::Formatter3164 {
+        facility: syslog::Facility::LOG_DAEMON,
+        hostname: None,
+        process: "hetzner-ddns".into(),
+        pid: std::process::id(),
+    };
+    let logger = syslog::unix(formatter)
+        .map_err(|e| format!("could not connect to syslog: {}", e))?;
+    let filter = syslog_filter(level, env::var("RUST_LOG").ok().as_deref());
+    let max_level = filter.filter();
+    log::set_boxed_logger(Box::new(FilteredLogger { inner: syslog::BasicLogger::new(logger), filter }))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// The same filter the terminal logger uses, with `spec` from RUST_LOG.
+#[cfg(unix)]
+fn syslog_filter(level: LevelFilter, spec: Option<&str>) -> env_filter::Filter {
+    let mut filter = env_filter::Builder::new();
+    filter.filter_level(LevelFilter::Warn).filter_module(module_path!(), level);
+    if let Some(spec) = spec {
+        filter.parse(spec);
+    }
+    filter.build()
+}
+
+#[cfg(not(unix))]
+fn init_syslog(_level: LevelFilter) -> Result<(), Box<dyn std::error::Error>> {
+    Err("--syslog is only supported on Unix".into())
+}
+
+/// Passes on only the records `filter` lets through.
+#[cfg(unix)]
+struct FilteredLogger<L> {
+    inner: L,
+    filter: env_filter::Filter,
+}
+
+#[cfg(unix)]
+impl<L: log::Log> log::Log for FilteredLogger<L> {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.filter.matches(record) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+fn main() {
+    let args = Cli::parse();
+
+    if let Err(e) = init_logging(args.syslog, args.output == OutputFormat::Json, log_level(args.verbose, args.quiet || args.output == OutputFormat::Json)) {
+        eprintln!("❌ Error setting up logging: {}", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = run(args) {
+        error!("{}", describe(&*e));
+        std::process::exit(exit_code(&*e));
+    }
+}
+
+fn run(args: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let config = args.config.as_deref().map(load_config).transpose()?;
+
+    match dotenv() {
+        Ok(_) => {} // .env loaded
+        // With a config file the .env file is optional.
+        Err(DotenvError::Io(ref e)) if e.kind() == std::io::ErrorKind::NotFound && config.is_some() => {}
+        Err(DotenvError::Io(ref e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            error!("❌ Error: .env file not found. Please create one with DNS_FQDN=...");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("❌ Error loading .env file: {}", e);
+            std::process::exit(1);
+        }
+    }
+    //dotenv().ok();
+    let config_token = config.as_ref().and_then(|c| c.api_token.clone());
+    let api_token = find_api_token(&args, config_token)?;
+    let providers = resolve_providers(&args, config.as_ref())?;
+    let hosts = match config {
+        Some(config) => config.hosts,
+        None => {
+            let fqdns = if args.fqdn.is_empty() {
+                let dns_fqdn = env::var("DNS_FQDN").map_err(|_| "❌ Missing DNS_FQDN in environment (check .env file)")?;
+                dns_fqdn.split(',').map(str::to_string).collect()
+            } else {
+                args.fqdn.clone()
+            };
+            fqdns.into_iter()
+                .map(|fqdn| HostConfig { fqdn, ipv6: args.ipv6, ttl: None })
+                .collect()
+        }
+    };
+    // A config file sets TTLs per host; DNS_TTL belongs to the .env setup.
+    let ttl = match (args.ttl, env::var("DNS_TTL")) {
+        (Some(ttl), _) => Some(ttl),
+        (None, Ok(value)) if args.config.is_none() => Some(parse_ttl(&value).map_err(|e| format!("❌ Invalid DNS_TTL: {}", e))?),
+        (None, _) => None,
+    };
+    let cloud_token = if args.update_cloud_rdns {
+        Some(env::var("HCLOUD_TOKEN").map_err(|_| "❌ Missing HCLOUD_TOKEN in environment (required for --update-cloud-rdns)")?)
+    } else {
+        None
+    };
+
+    let hosts = normalize_hosts(hosts, ttl)?;
+    check_rdns_hosts(&args, &hosts)?;
+
+    let name_pattern = args.name_pattern.as_deref()
+        .map(Regex::new)
+        .transpose()
+        .map_err(|e| format!("❌ Invalid --name-pattern: {}", e))?;
+
+    let explicit_value = if args.value_stdin {
+        Some(read_value_stdin(&args.record_type)?)
+    } else if let Some(path) = &args.value_file {
+        Some(read_value_file(path, &args.record_type)?)
+    } else {
+        None
+    };
+
+    let client = build_client(&args)?;
+    let shutdown = Shutdown::default();
+    let retry = Retry::new(args.retries).retry_on(&args.retry_on).with_shutdown(shutdown.clone());
+    let mut api = HetznerDnsClient::new(api_token)
+        .with_client(client.clone())
+        .with_rate_limit(args.rate_limit)
+        .with_retry(retry.clone());
+    let hooks = Hooks {
+        event_pipe: args.event_pipe.clone(),
+        notify_url: args.notify_url.clone(),
+        on_change: args.on_change.clone(),
+        client: client.clone(),
+    };
+    if args.ipv4_address.as_deref() == Some("-") && args.ipv6_address.as_deref() == Some("-") {
+        return Err("❌ Only one of --ipv4-address and --ipv6-address can be read from stdin".into());
+    }
+    let ipv4_address = args.ipv4_address.as_deref()
+        .map(|v| given_address("--ipv4-address", v, args.allow_private, is_public_ipv4))
+        .transpose()?;
+    let ipv6_address = args.ipv6_address.as_deref()
+        .map(|v| given_address("--ipv6-address", v, args.allow_private, is_public_ipv6))
+        .transpose()?;
+
+    let mut setup = Setup {
+        hosts,
+        ttl,
+        cloud_token,
+        name_pattern,
+        providers,
+        retry,
+        state_file: args.state_file.clone().map(|path| path.map_or_else(default_state_file, Ok)).transpose()?,
+        hooks,
+        ipv4_address,
+        ipv6_address,
+        shutdown,
+    };
+
+    check_providers(&args, &setup.providers, &setup, &setup.hosts)?;
+
+    if args.command == Some(Action::Status) {
+        if args.daemon || args.dry_run {
+            return Err("❌ status doesn't change anything; --daemon and --dry-run don't apply".into());
+        }
+        if !run_status(&args, &setup, &client, &mut api)? {
+            std::process::exit(EXIT_DRIFT);
+        }
+        return Ok(());
+    }
+
+    if args.daemon {
+        return run_daemon(&args, &mut setup, &client, &mut api);
+    }
+
+    let mut metrics = args.metrics_file.as_deref().map(Metrics::load);
+    let value = explicit_value.map(ValueSource::Explicit);
+    let result = run_cycle(&args, &setup, &client, &mut api, value, &mut CycleMemory::default(), &mut metrics);
+    if let (Some(metrics), Some(path)) = (&mut metrics, &args.metrics_file) {
+        metrics.finish(path, result.is_ok(), &api.usage());
+    }
+    let pending = result?;
+
+    if pending > 0 {
+        info!("📋 {} change(s) pending (--dry-run).", pending);
+        std::process::exit(2);
+    }
+    Ok(())
+}
+
+/// Checks every `--interval` seconds until SIGINT/SIGTERM, which also cuts
+/// short retries and --verify polling. A failed cycle is logged and retried
+/// on the next tick. After SIGHUP the next cycle first re-reads --config.
+fn run_daemon(args: &Cli, setup: &mut Setup, client: &Client, api: &mut HetznerDnsClient) -> Result<(), Box<dyn std::error::Error>> {
+    let shutdown = setup.shutdown.clone();
+    ctrlc::set_handler(move || shutdown.trigger())?;
+    #[cfg(unix)]
+    handle_sighup().map_err(|e| format!("❌ Could not install the SIGHUP handler: {}", e))?;
+
+    let last_success = Arc::new(AtomicU64::new(0));
+    if let Some(addr) = args.health_addr {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| format!("❌ Could not listen on --health-addr {}: {}", addr, e))?;
+        info!("🩺 Serving health checks on http://{}/healthz", addr);
+        serve_health(listener, Arc::clone(&last_success), args.interval.saturating_mul(2).max(60));
+    }
+
+    let mut replay = args.replay.as_deref().map(read_replay).transpose()?.map(Vec::into_iter);
+
+    let interval = Duration::from_secs(args.interval);
+    let mut memory = CycleMemory::default();
+    let mut metrics = args.metrics_file.as_deref().map(Metrics::load);
+    info!("🔁 Running as a daemon, checking every {}s", args.interval);
+
+    loop {
+        if RELOAD_REQUESTED.swap(false, Ordering::Relaxed) && reload_config(args, setup, api) {
+            // The new hosts haven't been checked yet.
+            memory.last_pushed = None;
+        }
+        let value = match &mut replay {
+            Some(entries) => match entries.next() {
+                Some(value) => Some(value),
+                None => {
+                    info!("⏹️ Reached the end of the --replay file.");
+                    break;
+                }
+            },
+            None => None,
+        };
+        let result = run_cycle(args, setup, client, api, value, &mut memory, &mut metrics);
+        if let (Some(metrics), Some(path)) = (&mut metrics, &args.metrics_file) {
+            metrics.finish(path, result.is_ok(), &api.usage());
+        }
+        if result.is_ok() {
+            last_success.store(unix_now(), Ordering::Relaxed);
+        }
+        if let Err(e) = result {
+            // A rejected token won't fix itself; let the supervisor see it.
+            if is_auth_error(&*e) {
+                return Err(e);
+            }
+            // Failures from a cycle cut short by the shutdown aren't news.
+            if !setup.shutdown.is_triggered() {
+                error!("{}", describe(&*e));
+            }
+        }
+
+        if !setup.shutdown.sleep(interval) {
+            break;
+        }
+    }
+
+    info!("👋 Shutting down.");
+    Ok(())
+}
+
+/// Set on SIGHUP; the daemon re-reads --config before its next cycle.
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn request_reload(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Routes SIGHUP to a config reload. Has to run after ctrlc::set_handler,
+/// which otherwise treats SIGHUP as a request to shut down.
+#[cfg(unix)]
+fn handle_sighup() -> std::io::Result<()> {
+    let handler: extern "C" fn(libc::c_int) = request_reload;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    if unsafe { libc::signal(libc::SIGHUP, handler as libc::sighandler_t) } == libc::SIG_ERR {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Re-reads --config after SIGHUP and swaps in its hosts, detection URLs,
+/// consensus and API token (which is looked up again, so a rotated
+/// --token-file is picked up too). An invalid file is logged and the daemon
+/// keeps the config it had. Returns whether the config was replaced.
+fn reload_config(args: &Cli, setup: &mut Setup, api: &mut HetznerDnsClient) -> bool {
+    let Some(path) = &args.config else {
+        warn!("⚠️ Got SIGHUP, but there is no --config file to reload");
+        return false;
+    };
+    let reloaded = load_config(path).and_then(|config| {
+        let providers = resolve_providers(args, Some(&config))?;
+        let token = find_api_token(args, config.api_token)?;
+        let hosts = normalize_hosts(config.hosts, setup.ttl)?;
+        check_rdns_hosts(args, &hosts)?;
+        check_providers(args, &providers, setup, &hosts)?;
+        Ok((hosts, providers, token))
+    });
+    let (hosts, providers, token) = match reloaded {
+        Ok(reloaded) => reloaded,
+        Err(e) => {
+            error!("{} (keeping the previous config)", e);
+            return false;
+        }
+    };
+
+    let mut changes = host_changes(&setup.hosts, &hosts);
+    changes.extend(provider_changes(&setup.providers, &providers));
+    if api.set_token(token) {
+        changes.push("API token changed".to_string());
+    }
+    if changes.is_empty() {
+        info!("🔄 Reloaded {}, nothing changed", path.display());
+    } else {
+        info!("🔄 Reloaded {}: {}", path.display(), changes.join("; "));
+    }
+    setup.hosts = hosts;
+    setup.providers = providers;
+    true
+}
+
+/// Describes how the detection settings changed, for the reload log.
+fn provider_changes(old: &Providers, new: &Providers) -> Vec<String> {
+    let mut changes = Vec::new();
+    if old.v4 != new.v4 {
+        changes.push(format!("ipv4_providers {}", new.v4.join(", ")));
+    }
+    if old.v6 != new.v6 {
+        changes.push(format!("ipv6_providers {}", new.v6.join(", ")));
+    }
+    if old.v4_consensus != new.v4_consensus {
+        changes.push(format!("ipv4_consensus {} → {}", old.v4_consensus, new.v4_consensus));
+    }
+    if old.v6_consensus != new.v6_consensus {
+        changes.push(format!("ipv6_consensus {} → {}", old.v6_consensus, new.v6_consensus));
+    }
+    changes
+}
+
+/// Describes how the host list changed, for the reload log.
+fn host_changes(old: &[HostConfig], new: &[HostConfig]) -> Vec<String> {
+    let show_ttl = |ttl: Option<u32>| ttl.map_or("unset".to_string(), |t| t.to_string());
+    let mut changes = Vec::new();
+    for host in new {
+        match old.iter().find(|h| h.fqdn == host.fqdn) {
+            None => changes.push(format!("added {}", host.fqdn)),
+            Some(before) => {
+                if before.ttl != host.ttl {
+                    changes.push(format!("{} ttl {} → {}", host.fqdn, show_ttl(before.ttl), show_ttl(host.ttl)));
+                }
+                if before.ipv6 != host.ipv6 {
+                    changes.push(format!("{} ipv6 {} → {}", host.fqdn, before.ipv6, host.ipv6));
+                }
+            }
+        }
+    }
+    for host in old.iter().filter(|h| !new.iter().any(|n| n.fqdn == h.fqdn)) {
+        changes.push(format!("removed {}", host.fqdn));
+    }
+    changes
 }
 
-#[derive(Deserialize)]
-struct ZoneList {
-    zones: Vec<Zone>,
+/// Answers --health-addr requests from a background thread, based on the
+/// Unix time of the last successful cycle (0 before the first one).
+fn serve_health(listener: TcpListener, last_success: Arc<AtomicU64>, max_age: u64) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|mut stream| answer_health_check(&mut stream, &last_success, max_age));
+            if let Err(e) = result {
+                debug!("Health check failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Reads one HTTP request and answers GET /healthz with 200 if a cycle
+/// succeeded within `max_age` seconds, or 503.
+fn answer_health_check(stream: &mut TcpStream, last_success: &AtomicU64, max_age: u64) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let mut reader = BufReader::new(&*stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Read the headers too, so the client doesn't see a reset.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let age = unix_now().saturating_sub(last_success.load(Ordering::Relaxed));
+    let (status, body) = match request_line.split_whitespace().nth(1) {
+        Some("/healthz") if last_success.load(Ordering::Relaxed) > 0 && age <= max_age => ("200 OK", "ok\n"),
+        Some("/healthz") => ("503 Service Unavailable", "no successful update recently\n"),
+        _ => ("404 Not Found", "not found\n"),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    )
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-struct Record {
-    id: String,
+/// One row of `status`: a record next to the address it should hold.
+#[derive(Serialize, Debug, PartialEq)]
+struct StatusRow {
+    fqdn: String,
     #[serde(rename = "type")]
     record_type: String,
-    name: String,
-    value: String,
-    zone_id: String,
+    /// The value at Hetzner; None if the record doesn't exist.
+    current: Option<String>,
+    detected: Option<String>,
     ttl: Option<u32>,
+    drift: bool,
 }
 
-#[derive(Deserialize)]
-struct RecordList {
-    records: Vec<Record>,
+impl StatusRow {
+    fn new(fqdn: &str, record_type: &str, record: Option<&Record>, detected: Option<&str>) -> Self {
+        // Without a detected address there is nothing to compare against.
+        let drift = match (record, detected) {
+            (Some(record), Some(detected)) => record.value != detected,
+            (None, Some(_)) => true,
+            (_, None) => false,
+        };
+        StatusRow {
+            fqdn: fqdn.to_string(),
+            record_type: record_type.to_string(),
+            current: record.map(|r| r.value.clone()),
+            detected: detected.map(str::to_string),
+            ttl: record.and_then(|r| r.ttl),
+            drift,
+        }
+    }
 }
 
-#[derive(Parser, Debug)]
-#[command(name = "hetzner-ddns", version, about = "Dynamic DNS updater for Hetzner")]
-struct Cli {
-    /// Update the AAAA (IPv6) record as well
-    #[arg(long)]
-    ipv6: bool,
+/// What `status --output json` prints.
+#[derive(Serialize)]
+struct StatusReport {
+    ipv4: String,
+    ipv6: Option<String>,
+    drift: bool,
+    records: Vec<StatusRow>,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Cli::parse();
-    let update_ipv6 = args.ipv6;
+/// Compares the records of every configured hostname with the detected
+/// addresses and prints them. Only reads from the API. Returns whether
+/// everything matches.
+fn run_status(args: &Cli, setup: &Setup, client: &Client, api: &mut HetznerDnsClient) -> Result<bool, Box<dyn std::error::Error>> {
+    let (ip4, ip6) = detect_ips(client, args, setup)?;
+    let fqdns: Vec<&str> = setup.hosts.iter().map(|h| h.fqdn.as_str()).collect();
+    let zones = api.zones_for(&fqdns)?;
 
-    match dotenv() {
-        Ok(_) => {} // .env loaded
-        Err(DotenvError::Io(ref e)) if e.kind() == std::io::ErrorKind::NotFound => {
-            eprintln!("❌ Error: .env file not found. Please create one with DNS_FQDN=...");
-            std::process::exit(1);
+    let mut rows = Vec::new();
+    let mut zone_records: Vec<(&str, Vec<Record>)> = Vec::new();
+    let mut failures = 0;
+    for config in &setup.hosts {
+        let (name, zone) = match split_fqdn(&config.fqdn, &zones) {
+            Ok(found) => found,
+            Err(e) => {
+                error!("{}", e);
+                failures += 1;
+                continue;
+            }
+        };
+        if !zone_records.iter().any(|(id, _)| *id == zone.id) {
+            zone_records.push((&zone.id, api.list_records(&zone.id)?));
         }
-        Err(e) => {
-            eprintln!("❌ Error loading .env file: {}", e);
-            std::process::exit(1);
+        let records = zone_records.iter()
+            .find(|(id, _)| *id == zone.id)
+            .map(|(_, records)| records.as_slice())
+            .unwrap_or_default();
+
+        rows.push(StatusRow::new(&config.fqdn, "A", find_record(records, &name, "A", &zone.id), Some(&ip4)));
+        if config.ipv6 {
+            rows.push(StatusRow::new(&config.fqdn, "AAAA", find_record(records, &name, "AAAA", &zone.id), ip6.as_deref()));
         }
     }
-    //dotenv().ok();
-    let api_token = env::var("HETZNER_API_TOKEN").map_err(|_| "❌ Missing HETZNER_API_TOKEN in environment (check .env file)")?;
-    let dns_fqdn = env::var("DNS_FQDN").map_err(|_| "❌ Missing DNS_FQDN in environment (check .env file)")?;
-
-    // Split domain from record
-    let parts: Vec<&str> = dns_fqdn.split('.').collect();
-    if parts.len() < 2 {
-        return Err("DNS_FQDN must be a valid FQDN (e.g. dyndns.example.com)".into());
-    }
-
-    let record_name = parts[0].to_string();
-    let zone_name = parts[1..].join(".");
-
-    let client = Client::new();
-
-    // Fetch public IPs
-    let ip4 = client.get("https://ipv4.icanhazip.com").send()?.text()?.trim().to_string();
-    let ip6 = client.get("https://ipv6.icanhazip.com").send().ok()
-        .and_then(|r| r.text().ok())
-        .map(|s| s.trim().to_string());
-
-    // Get Zone ID
-    let zones: ZoneList = client.get("https://dns.hetzner.com/api/v1/zones")
-        .header("Auth-API-Token", &api_token)
-        .send()?.json()?;
-
-    let zone = zones.zones.iter().find(|z| z.name == zone_name)
-        .ok_or("❌ Zone not found")?;
-
-    // Get DNS record
-    let records: RecordList = client.get(format!("https://dns.hetzner.com/api/v1/records?zone_id={}", zone.id))
-        .header("Auth-API-Token", &api_token)
-        .send()?.json()?;
-
-        // --- IPv4 (A) Record ---
-    if let Some(record4) = records.records.iter().find(|r| r.name == record_name && r.record_type == "A") {
-        if record4.value != ip4 {
-            println!("🔄 Updating A record from {} to {}", record4.value, ip4);
-            let updated4 = Record {
-                value: ip4.clone(),
-                ttl: Some(60),
-                ..record4.to_owned()
-            };
 
-            client.put(format!("https://dns.hetzner.com/api/v1/records/{}", record4.id))
-                .header("Auth-API-Token", &api_token)
-                .header("Content-Type", "application/json")
-                .json(&updated4)
-                .send()?;
-            println!("✅ A record updated.");
+    let drift = rows.iter().any(|r| r.drift);
+    if args.output == OutputFormat::Json {
+        let report = StatusReport { ipv4: ip4, ipv6: ip6, drift, records: rows };
+        println!("{}", serde_json::to_string(&report)?);
+    } else {
+        for line in status_table(&rows) {
+            println!("{}", line);
+        }
+    }
+
+    if failures > 0 {
+        return Err(format!("❌ {} of {} hostnames failed", failures, setup.hosts.len()).into());
+    }
+    Ok(!drift)
+}
+
+/// Formats the status rows as an aligned table with a header.
+fn status_table(rows: &[StatusRow]) -> Vec<String> {
+    let cells: Vec<[String; 6]> = std::iter::once(["FQDN", "TYPE", "CURRENT", "DETECTED", "TTL", "DRIFT"].map(String::from))
+        .chain(rows.iter().map(|r| [
+            r.fqdn.clone(),
+            r.record_type.clone(),
+            r.current.clone().unwrap_or_else(|| "(missing)".to_string()),
+            r.detected.clone().unwrap_or_else(|| "-".to_string()),
+            format_ttl(r.ttl),
+            if r.drift { "yes" } else { "no" }.to_string(),
+        ]))
+        .collect();
+
+    let mut widths = [0; 6];
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    cells.iter()
+        .map(|row| {
+            let line: Vec<String> = row.iter().zip(widths).map(|(cell, width)| format!("{:<width$}", cell)).collect();
+            line.join("  ").trim_end().to_string()
+        })
+        .collect()
+}
+
+/// What a daemon carries from one cycle to the next.
+#[derive(Default)]
+struct CycleMemory {
+    /// The addresses of the last successful cycle.
+    last_pushed: Option<ValueSource>,
+    /// Cycles in a row without a public IPv6 address.
+    ipv6_misses: u32,
+    /// Zones already checked for DNSSEC, by id.
+    dnssec_checked: Vec<String>,
+}
+
+/// Cycles in a row the daemon must find no IPv6 address before
+/// --prune-missing-aaaa deletes AAAA records. A single run relies on the
+/// detection retries instead.
+const PRUNE_AFTER_MISSES: u32 = 3;
+
+/// TTLs below this make DNSSEC validation fragile while a change propagates.
+const DNSSEC_MIN_TTL: u32 = 300;
+
+/// Runs one cycle and reports it, however it ends: to the metrics and, with
+/// --output json, on stdout. Returns how many changes --dry-run held back.
+fn run_cycle(
+    args: &Cli,
+    setup: &Setup,
+    client: &Client,
+    api: &mut HetznerDnsClient,
+    value: Option<ValueSource>,
+    memory: &mut CycleMemory,
+    metrics: &mut Option<Metrics>,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    api.reset_usage();
+    let mut outcome = CycleOutcome::default();
+    let result = sync_cycle(args, setup, client, api, value, memory, &mut outcome);
+    report_unfinished(args, setup, &mut outcome, &result);
+
+    if let Some(metrics) = metrics {
+        metrics.observe(outcome.source.as_ref(), &outcome.changes.report, unix_now());
+    }
+    print_report(args, outcome, result.as_ref().err().map(|e| &**e));
+    result
+}
+
+/// Reports the hostnames a cycle didn't get to: as failed when it ended with
+/// an error, as skipped when a guard skipped it.
+fn report_unfinished(args: &Cli, setup: &Setup, outcome: &mut CycleOutcome, result: &Result<u32, Box<dyn std::error::Error>>) {
+    let action = match (result, outcome.skipped) {
+        (_, Some(_)) => RecordAction::Skipped,
+        (Err(_), None) => RecordAction::Failed,
+        (Ok(_), None) => return,
+    };
+    let report = &mut outcome.changes.report;
+    for host in &setup.hosts {
+        if report.iter().any(|r| r.fqdn == host.fqdn) {
+            continue;
+        }
+        let wanted: Vec<(&str, Option<&str>)> = match &outcome.source {
+            Some(source) => wanted_values(args, host, source).into_iter().map(|(t, v)| (t, Some(v))).collect(),
+            None if host.ipv6 => vec![("A", None), ("AAAA", None)],
+            None => vec![("A", None)],
+        };
+        for (record_type, value) in wanted {
+            let entry = RecordReport::new(&host.fqdn, record_type, None, value, action);
+            report.push(match result {
+                Err(e) => entry.failed(e),
+                Ok(_) => entry,
+            });
+        }
+    }
+}
+
+/// What a cycle got to before it ended, for run_cycle to report.
+#[derive(Default)]
+struct CycleOutcome {
+    /// The addresses or value, once known.
+    source: Option<ValueSource>,
+    changes: Changes,
+    /// Set when --guard-cmd or --skip-on-metered skipped the cycle.
+    skipped: Option<&'static str>,
+}
+
+/// Detects the addresses, unless `value` already holds them, and brings the
+/// records of every configured hostname up to date once. If the last cycle
+/// pushed the same addresses, the Hetzner API is skipped. A failing hostname
+/// doesn't stop the others, but fails the cycle.
+fn sync_cycle(
+    args: &Cli,
+    setup: &Setup,
+    client: &Client,
+    api: &mut HetznerDnsClient,
+    value: Option<ValueSource>,
+    memory: &mut CycleMemory,
+    outcome: &mut CycleOutcome,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    if let Some(cmd) = &args.guard_cmd {
+        if !guard_allows(cmd)? {
+            outcome.skipped = Some("guard_cmd");
+            return Ok(0);
+        }
+    }
+
+    if args.skip_on_metered && skip_for_metered() {
+        outcome.skipped = Some("metered");
+        return Ok(0);
+    }
+
+    let source = if let Some(value) = value {
+        value
+    } else {
+        // Fetch public IPs
+        let (ip4, ip6) = detect_ips(client, args, setup)?;
+
+        if args.require_public_ip {
+            let addr: Ipv4Addr = ip4.parse()
+                .map_err(|_| format!("❌ Detected IPv4 address is not valid: {}", ip4))?;
+            if !is_public_ipv4(&addr) {
+                return Err(format!("❌ Detected IPv4 address {} is not public (--require-public-ip)", addr).into());
+            }
+        }
+
+        ValueSource::Detected { ip4, ip6 }
+    };
+    outcome.source = Some(source.clone());
+
+    if let ValueSource::Detected { ip6, .. } = &source {
+        if ip6.is_none() && setup.wants_ipv6(args) {
+            memory.ipv6_misses += 1;
+        } else {
+            memory.ipv6_misses = 0;
+        }
+    }
+    let misses_needed = if args.daemon { PRUNE_AFTER_MISSES } else { 1 };
+    let prune_aaaa = args.prune_missing_aaaa && memory.ipv6_misses >= misses_needed;
+    if args.prune_missing_aaaa && memory.ipv6_misses > 0 && !prune_aaaa {
+        warn!("⚠️ No public IPv6 address ({} of {} checks), not pruning AAAA records yet.", memory.ipv6_misses, misses_needed);
+    }
+
+    // The cycle that makes the IPv6 loss conclusive still has to prune.
+    let prune_due = prune_aaaa && memory.ipv6_misses == misses_needed;
+    let changes = &mut outcome.changes;
+    if memory.last_pushed.as_ref() == Some(&source) && !prune_due {
+        info!("✅ Public IP unchanged since the last update, skipping Hetzner API.");
+        for config in &setup.hosts {
+            for (record_type, value) in wanted_values(args, config, &source) {
+                changes.report.push(RecordReport::new(&config.fqdn, record_type, Some(value), Some(value), RecordAction::Unchanged));
+            }
+        }
+        return Ok(0);
+    }
+
+    changes.dry_run = args.dry_run;
+    changes.prune_aaaa = prune_aaaa;
+
+    // Hostnames whose records the state file says are current are left out.
+    // --name-pattern doesn't know its hostnames up front, and --dry-run
+    // always asks the API.
+    let use_cache = !(args.no_cache || args.force || args.dry_run || args.bypasses_cache()) && setup.name_pattern.is_none();
+    let mut state = setup.state_file.as_deref().map(State::load);
+    let mut to_check = Vec::new();
+    for config in &setup.hosts {
+        let wanted = wanted_values(args, config, &source);
+        // A cached AAAA record that is due for pruning needs the API.
+        let cached = state.as_ref().is_some_and(|s| {
+            s.matches(&config.fqdn, &wanted, config.ttl.filter(|_| args.update_ttl)) && !(prune_aaaa && config.ipv6 && s.has(&config.fqdn, "AAAA"))
+        });
+        if use_cache && cached {
+            info!("✅ {} unchanged (cached)", config.fqdn);
+            for (record_type, value) in wanted {
+                changes.report.push(RecordReport::new(&config.fqdn, record_type, Some(value), Some(value), RecordAction::Unchanged));
+            }
         } else {
-            println!("✅ A record already up to date: {}", ip4);
+            to_check.push(config);
+        }
+    }
+    if to_check.is_empty() {
+        memory.last_pushed = Some(source);
+        return Ok(0);
+    }
+
+    // Get Zone IDs, once for all hostnames
+    let fqdns: Vec<&str> = to_check.iter().map(|h| h.fqdn.as_str()).collect();
+    let zones = api.zones_for(&fqdns)?;
+    let mut failures = Failures::default();
+
+    // Hostnames that fail before any record is looked at are reported with
+    // the record they would have set first.
+    let (first_type, first_value) = match &source {
+        ValueSource::Explicit(value) => (args.record_type.as_str(), value.as_str()),
+        ValueSource::Detected { ip4, .. } => ("A", ip4.as_str()),
+    };
+
+    let mut hosts = Vec::new();
+    for config in to_check {
+        match split_fqdn(&config.fqdn, &zones) {
+            Ok((record_name, zone)) => hosts.push(Host { config, record_name, zone }),
+            Err(e) => {
+                error!("{}", e);
+                changes.report.push(RecordReport::new(&config.fqdn, first_type, None, Some(first_value), RecordAction::Failed).failed(&e));
+                failures.add_code(1, 1);
+            }
+        }
+    }
+
+    warn_dnssec_ttl(&hosts, memory);
+
+    // Get DNS records, once per distinct zone
+    let mut zone_records: Vec<(&Zone, Vec<Record>)> = Vec::new();
+    let mut zone_errors: Vec<(&str, String, i32)> = Vec::new();
+    for host in &hosts {
+        if zone_records.iter().any(|(zone, _)| zone.id == host.zone.id)
+            || zone_errors.iter().any(|(id, _, _)| *id == host.zone.id) {
+            continue;
+        }
+        match api.list_records(&host.zone.id) {
+            Ok(records) => zone_records.push((host.zone, records)),
+            // Every zone uses the same token, so the others would fail too.
+            Err(e) if is_auth_error(&*e) => return Err(e),
+            Err(e) => {
+                let code = exit_code(&*e);
+                let e = format!("❌ Could not fetch records of zone {}: {}", host.zone.name, describe(&*e));
+                error!("{}", e);
+                zone_errors.push((&host.zone.id, e, code));
+            }
+        }
+    }
+
+    let before: Vec<Record> = zone_records.iter().flat_map(|(_, records)| records.iter().cloned()).collect();
+    if let Some(path) = &args.snapshot_before {
+        write_snapshot(path, &before)?;
+    }
+
+    if let (Some(pattern), ValueSource::Detected { ip4, ip6 }) = (&setup.name_pattern, &source) {
+        for (zone, records) in &zone_records {
+            // AAAA records are only matched in zones of a host with IPv6 on.
+            let ipv6 = hosts.iter().any(|h| h.zone.id == zone.id && h.config.ipv6);
+            if ipv6 && ip6.is_none() {
+                warn!("⚠️ No public IPv6 address found. Skipping AAAA update in {}.", zone.name);
+            }
+            let ip6 = ip6.as_deref().filter(|_| ipv6);
+            if let Err(e) = sync_matching(args, pattern, zone, records, (ip4, ip6), setup, changes) {
+                if is_auth_error(&*e) {
+                    return Err(e);
+                }
+                error!("{}", describe(&*e));
+                failures.add(1, &*e);
+            }
         }
     } else {
-        println!("⚠️ A record not found.");
-    }
-
-   // --- IPv6 (AAAA) Record ---
-    if update_ipv6 {
-        if let Some(ip6) = ip6 {
-            if let Some(record6) = records.records.iter().find(|r| r.name == record_name && r.record_type == "AAAA") {
-                if record6.value != ip6 {
-                    println!("🔄 Updating AAAA record from {} to {}", record6.value, ip6);
-                    let updated6 = Record {
-                        value: ip6.clone(),
-                        ttl: Some(60),
-                        ..record6.to_owned()
-                    };
-
-                    client.put(format!("https://dns.hetzner.com/api/v1/records/{}", record6.id))
-                        .header("Auth-API-Token", &api_token)
-                        .header("Content-Type", "application/json")
-                        .json(&updated6)
-                        .send()?;
-                    println!("✅ AAAA record updated.");
+        for host in &hosts {
+            let Some((_, records)) = zone_records.iter().find(|(zone, _)| zone.id == host.zone.id) else {
+                if let Some((_, e, code)) = zone_errors.iter().find(|(id, _, _)| *id == host.zone.id) {
+                    changes.report.push(RecordReport::new(&host.config.fqdn, first_type, None, Some(first_value), RecordAction::Failed).failed(e));
+                    failures.add_code(1, *code);
+                }
+                continue;
+            };
+            if let Err(e) = sync_host(args, api, host, records, &source, changes) {
+                // Every host uses the same token, so the others would fail too.
+                if is_auth_error(&*e) {
+                    return Err(e);
+                }
+                error!("{}", describe(&*e));
+                if !changes.report.iter().any(|r| r.fqdn == host.config.fqdn) {
+                    changes.report.push(RecordReport::new(&host.config.fqdn, first_type, None, Some(first_value), RecordAction::Failed).failed(&e));
+                }
+                failures.add(1, &*e);
+            }
+        }
+    }
+
+    for kind in apply_updates(api, std::mem::take(&mut changes.updates), &mut changes.report)? {
+        failures.add_code(1, kind_code(kind));
+    }
+
+    // Only records that were really updated or created are announced,
+    // whether or not --verify sees them on the nameservers yet. AAAA records
+    // removed by --prune-missing-aaaa are not.
+    for entry in &changes.report {
+        if matches!(entry.action, RecordAction::Updated | RecordAction::Created) {
+            setup.hooks.fire(&ChangeEvent {
+                fqdn: &entry.fqdn,
+                record_type: &entry.record_type,
+                old_value: entry.previous.as_deref().unwrap_or(""),
+                new_value: entry.new.as_deref().unwrap_or(""),
+                timestamp: unix_now(),
+            });
+        }
+    }
+
+    if args.verify {
+        failures.add_code(verify_changes(&mut changes.report, &zones, Duration::from_secs(args.verify_timeout), &setup.shutdown), 1);
+    }
+
+    if args.terraform_import {
+        for record in &changes.processed {
+            let zone_name = zones.iter().find(|z| z.id == record.zone_id).map_or(record.zone_id.as_str(), |z| z.name.as_str());
+            println!("{}", terraform_import_block(record, zone_name));
+        }
+    }
+
+    if args.recheck_ip {
+        if let ValueSource::Detected { ip4, ip6 } = &source {
+            recheck_ips(client, args, setup, ip4, ip6.as_deref());
+        }
+    }
+
+    // --- Hetzner Cloud reverse DNS ---
+    if let (Some(cloud_token), Some(server_id)) = (&setup.cloud_token, args.server_id) {
+        if changes.dry_run {
+            info!("WOULD update reverse DNS of server {} to {}", server_id, setup.hosts[0].fqdn);
+        } else {
+            update_cloud_rdns(client, &setup.retry, cloud_token, server_id, &setup.hosts[0].fqdn)?;
+        }
+    }
+
+    if args.snapshot_after.is_some() || args.show_diff {
+        let mut after = Vec::new();
+        for (zone, _) in &zone_records {
+            after.extend(api.list_records(&zone.id)?);
+        }
+
+        if let Some(path) = &args.snapshot_after {
+            write_snapshot(path, &after)?;
+        }
+
+        if args.show_diff {
+            let lines = diff_records(&before, &after);
+            if lines.is_empty() {
+                info!("📋 No records changed.");
+            } else {
+                info!("📋 Record changes:");
+                for line in lines {
+                    info!("   {}", line);
+                }
+            }
+        }
+    }
+
+    debug!("{}", api.usage().summary());
+
+    if let (Some(state), Some(path), false) = (&mut state, &setup.state_file, args.dry_run) {
+        let ttl_of = |fqdn: &str| setup.hosts.iter().find(|h| h.fqdn == fqdn).and_then(|h| h.ttl);
+        state.record(&changes.report, ttl_of, args.update_ttl, unix_now());
+        if let Err(e) = state.save(path) {
+            warn!("⚠️ Could not write state file {}: {}", path.display(), e);
+        }
+    }
+
+    if failures.hosts > 0 {
+        return Err(Box::new(HostsFailed { failed: failures.hosts, total: setup.hosts.len(), code: failures.code }));
+    }
+
+    memory.last_pushed = Some(source);
+    Ok(changes.pending)
+}
+
+/// Prints the RunReport for --output json.
+fn print_report(args: &Cli, outcome: CycleOutcome, error: Option<&dyn std::error::Error>) {
+    if args.output != OutputFormat::Json {
+        return;
+    }
+    let (ipv4, ipv6) = match outcome.source {
+        Some(ValueSource::Detected { ip4, ip6 }) => (Some(ip4), ip6),
+        Some(ValueSource::Explicit(_)) | None => (None, None),
+    };
+    let report = RunReport {
+        ipv4,
+        ipv6,
+        records: outcome.changes.report,
+        skipped: outcome.skipped,
+        error: error.map(|e| e.to_string()),
+    };
+    match serde_json::to_string(&report) {
+        Ok(json) => println!("{}", json),
+        Err(e) => error!("❌ Could not serialize the run report: {}", e),
+    }
+}
+
+/// A configured hostname resolved to its zone.
+struct Host<'a> {
+    config: &'a HostConfig,
+    record_name: String,
+    zone: &'a Zone,
+}
+
+/// Warns once per zone when a host sets a TTL below DNSSEC_MIN_TTL in a zone
+/// the nameservers sign. Purely advisory: the update goes ahead.
+fn warn_dnssec_ttl(hosts: &[Host], memory: &mut CycleMemory) {
+    for host in hosts {
+        let Some(ttl) = host.config.ttl.filter(|ttl| *ttl < DNSSEC_MIN_TTL) else { continue };
+        if memory.dnssec_checked.contains(&host.zone.id) {
+            continue;
+        }
+        memory.dnssec_checked.push(host.zone.id.clone());
+        match zone_is_signed(host.zone) {
+            Ok(true) => warn!(
+                "⚠️ Zone {} is signed with DNSSEC; a TTL of {}s for {} can make validating resolvers fail while a change propagates (at least {}s is safer).",
+                host.zone.name, ttl, host.config.fqdn, DNSSEC_MIN_TTL
+            ),
+            Ok(false) => {}
+            Err(e) => debug!("Could not check zone {} for DNSSEC: {}", host.zone.name, e),
+        }
+    }
+}
+
+/// Brings the record(s) of one hostname up to date: the A (and AAAA) record
+/// for detected addresses, or the `--record-type` record for an explicit value.
+fn sync_host(
+    args: &Cli,
+    api: &mut HetznerDnsClient,
+    host: &Host,
+    records: &[Record],
+    source: &ValueSource,
+    changes: &mut Changes,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match source {
+        ValueSource::Explicit(value) => {
+            sync_record(args, api, host, records, &args.record_type, value, changes)?;
+        }
+        ValueSource::Detected { ip4, ip6 } => {
+            // --- IPv4 (A) Record ---
+            sync_record(args, api, host, records, "A", ip4, changes)?;
+
+            // --- IPv6 (AAAA) Record ---
+            if host.config.ipv6 {
+                if let Some(ip6) = ip6 {
+                    sync_record(args, api, host, records, "AAAA", ip6, changes)?;
+                } else if changes.prune_aaaa {
+                    prune_aaaa(api, changes, records, &host.record_name, &host.zone.id, &host.config.fqdn)?;
                 } else {
-                    println!("✅ AAAA record already up to date: {}", ip6);
+                    warn!("⚠️ No public IPv6 address found. Skipping AAAA update for {}.", host.config.fqdn);
                 }
+            } else if args.config.is_some() {
+                info!("ℹ️ Skipping AAAA update for {} (set ipv6 = true in its [[hosts]] entry to enable).", host.config.fqdn);
             } else {
-                println!("⚠️ AAAA record not found.");
+                info!("ℹ️ Skipping AAAA update (use --ipv6 to enable).");
             }
-        } else {
-            println!("ℹ️ No public IPv6 address found. Skipping AAAA update.");
         }
+    }
+    Ok(())
+}
+
+/// Updates, creates or reports a single record of a hostname.
+fn sync_record(
+    args: &Cli,
+    api: &mut HetznerDnsClient,
+    host: &Host,
+    records: &[Record],
+    record_type: &str,
+    value: &str,
+    changes: &mut Changes,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(record) = find_record(records, &host.record_name, record_type, &host.zone.id) {
+        plan_record(changes, record, value, host.config.ttl, args.compare(), args.force, &host.config.fqdn);
+    } else if args.create || (args.prune_missing_aaaa && record_type == "AAAA") {
+        // Pruned AAAA records come back once IPv6 does.
+        let new_record = NewRecord {
+            zone_id: host.zone.id.clone(),
+            record_type: record_type.to_string(),
+            name: host.record_name.clone(),
+            value: value.to_string(),
+            ttl: host.config.ttl,
+        };
+        create_record(api, changes, new_record, &host.config.fqdn)?;
     } else {
-        println!("ℹ️ Skipping AAAA update (use --ipv6 to enable).");
+        warn!("⚠️ {} record {} not found.", record_type, host.config.fqdn);
+        changes.report.push(RecordReport::new(&host.config.fqdn, record_type, None, Some(value), RecordAction::Missing));
+    }
+    Ok(())
+}
+
+/// Bulk update of every A record in the zone matching --name-pattern, and of
+/// every matching AAAA record if `ip6` is given.
+fn sync_matching(
+    args: &Cli,
+    pattern: &Regex,
+    zone: &Zone,
+    records: &[Record],
+    (ip4, ip6): (&str, Option<&str>),
+    setup: &Setup,
+    changes: &mut Changes,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ttl = setup.ttl;
+    let mut targets: Vec<(&Record, &str)> = records.iter()
+        .filter(|r| r.record_type == "A" && pattern.is_match(&r.name) && in_zone(r, &zone.id))
+        .map(|r| (r, ip4))
+        .collect();
+    if let Some(ip6) = ip6 {
+        targets.extend(records.iter()
+            .filter(|r| r.record_type == "AAAA" && pattern.is_match(&r.name) && in_zone(r, &zone.id))
+            .map(|r| (r, ip6)));
+    }
+
+    if targets.is_empty() {
+        warn!("⚠️ No records in {} match --name-pattern {}", zone.name, pattern);
+        return Ok(());
     }
 
+    info!("🔎 --name-pattern matched {} records in {}:", targets.len(), zone.name);
+    for (record, _) in &targets {
+        info!("   {} {} ({})", record.record_type, record.name, record.value);
+    }
+    if !args.yes && !args.dry_run && !confirm(&format!("Update these {} records?", targets.len()))? {
+        return Err(format!("❌ Refusing to update {} records without confirmation (use --yes)", targets.len()).into());
+    }
+
+    for (record, ip) in targets {
+        plan_record(changes, record, ip, ttl, args.compare(), args.force, &record_fqdn(&record.name, &zone.name));
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api(base_url: String) -> HetznerDnsClient {
+        HetznerDnsClient::new("token").with_base_url(base_url).with_rate_limit(1000.0)
+    }
+
+    /// Settings for `hosts` that detect nothing and don't retry.
+    fn setup(hosts: Vec<HostConfig>) -> Setup {
+        Setup {
+            hosts,
+            ttl: None,
+            cloud_token: None,
+            name_pattern: None,
+            providers: Providers { v4: Vec::new(), v6: Vec::new(), v4_consensus: 1, v6_consensus: 1, timeout: PROVIDER_TIMEOUT },
+            retry: Retry::none(),
+            state_file: None,
+            ipv4_address: None,
+            ipv6_address: None,
+            hooks: Hooks { event_pipe: None, notify_url: None, on_change: None, client: Client::new() },
+            shutdown: Shutdown::default(),
+        }
+    }
+
+    fn host(fqdn: &str, ipv6: bool) -> HostConfig {
+        HostConfig { fqdn: fqdn.to_string(), ipv6, ttl: None }
+    }
+
+    /// An A record in zone z1.
+    fn record(id: &str, name: &str, value: &str) -> Record {
+        Record {
+            id: id.to_string(),
+            record_type: "A".to_string(),
+            name: name.to_string(),
+            value: value.to_string(),
+            zone_id: "z1".to_string(),
+            ttl: None,
+        }
+    }
+
+    #[test]
+    fn failed_put_exits_with_the_transient_code() {
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/zones")
+            .match_query(mockito::Matcher::Any)
+            .with_body(r#"{"zones":[{"id":"z1","name":"example.com"}]}"#)
+            .create();
+        server.mock("GET", "/records")
+            .match_query(mockito::Matcher::UrlEncoded("zone_id".into(), "z1".into()))
+            .with_body(r#"{"records":[{"id":"1","type":"A","name":"home","value":"192.0.2.1","zone_id":"z1"}]}"#)
+            .create();
+        let put = server.mock("PUT", "/records/1")
+            .with_status(503)
+            .with_body("<html>Service Unavailable</html>")
+            .create();
+
+        let args = Cli::try_parse_from(["hetzner-ddns", "--fqdn", "home.example.com"]).unwrap();
+        let setup = Setup {
+            ipv4_address: Some("203.0.113.7".parse().unwrap()),
+            ..setup(vec![host("home.example.com", false)])
+        };
+        let err = run_cycle(&args, &setup, &Client::new(), &mut api(server.url()), None, &mut CycleMemory::default(), &mut None)
+            .unwrap_err();
+
+        put.assert();
+        assert_eq!(err.to_string(), "❌ 1 of 1 hostnames failed");
+        assert_eq!(exit_code(&*err), EXIT_TRANSIENT);
+    }
+
+    #[test]
+    fn name_pattern_updates_aaaa_only_in_zones_of_ipv6_hosts() {
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/zones")
+            .match_query(mockito::Matcher::Any)
+            .with_body(r#"{"zones":[{"id":"z1","name":"example.com"},{"id":"z2","name":"other.de"}]}"#)
+            .create();
+        for (zone, a, aaaa) in [("z1", "1", "2"), ("z2", "3", "4")] {
+            server.mock("GET", "/records")
+                .match_query(mockito::Matcher::UrlEncoded("zone_id".into(), zone.into()))
+                .with_body(format!(
+                    r#"{{"records":[{{"id":"{a}","type":"A","name":"www","value":"192.0.2.1","zone_id":"{zone}"}},{{"id":"{aaaa}","type":"AAAA","name":"www","value":"2001:db8::1","zone_id":"{zone}"}}]}}"#
+                ))
+                .create();
+        }
+        server.mock("PUT", "/records/bulk")
+            .with_body(r#"{"records":[{"id":"1"},{"id":"2"},{"id":"3"}]}"#)
+            .create();
+
+        let args = Cli::try_parse_from(["hetzner-ddns", "--yes"]).unwrap();
+        let setup = Setup {
+            name_pattern: Some(Regex::new("^www$").unwrap()),
+            ..setup(vec![host("home.example.com", true), host("nas.other.de", false)])
+        };
+        let source = ValueSource::Detected { ip4: "203.0.113.7".into(), ip6: Some("2001:db8::7".into()) };
+        let mut outcome = CycleOutcome::default();
+        sync_cycle(&args, &setup, &Client::new(), &mut api(server.url()), Some(source), &mut CycleMemory::default(), &mut outcome).unwrap();
+
+        let updated: Vec<_> = outcome.changes.report.iter()
+            .map(|r| (r.id.as_deref().unwrap_or_default(), r.record_type.as_str(), r.action))
+            .collect();
+        assert_eq!(updated, [
+            ("1", "A", RecordAction::Updated),
+            ("2", "AAAA", RecordAction::Updated),
+            ("3", "A", RecordAction::Updated),
+        ]);
+    }
+
+    #[test]
+    fn daemon_prunes_aaaa_after_repeated_misses_and_recreates_it() {
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/zones")
+            .match_query(mockito::Matcher::Any)
+            .with_body(r#"{"zones":[{"id":"z1","name":"example.com"}]}"#)
+            .create();
+        let records = server.mock("GET", "/records")
+            .match_query(mockito::Matcher::Any)
+            .with_body(r#"{"records":[{"id":"1","type":"A","name":"home","value":"203.0.113.7","zone_id":"z1"},{"id":"2","type":"AAAA","name":"home","value":"2001:db8::1","zone_id":"z1"}]}"#)
+            .create();
+        let delete = server.mock("DELETE", "/records/2").expect(1).create();
+
+        let args = Cli::try_parse_from(["hetzner-ddns", "--daemon", "--prune-missing-aaaa", "--yes"]).unwrap();
+        let setup = setup(vec![host("home.example.com", true)]);
+        let mut api = api(server.url());
+        let mut memory = CycleMemory::default();
+        let mut cycle = |ip6: Option<&str>| {
+            let source = ValueSource::Detected { ip4: "203.0.113.7".into(), ip6: ip6.map(str::to_string) };
+            run_cycle(&args, &setup, &Client::new(), &mut api, Some(source), &mut memory, &mut None).unwrap();
+        };
+
+        cycle(None);
+        cycle(None);
+        assert!(!delete.matched());
+        cycle(None);
+        delete.assert();
+
+        // Once IPv6 is back the record is created again.
+        records.remove();
+        server.mock("GET", "/records")
+            .match_query(mockito::Matcher::Any)
+            .with_body(r#"{"records":[{"id":"1","type":"A","name":"home","value":"203.0.113.7","zone_id":"z1"}]}"#)
+            .create();
+        let create = server.mock("POST", "/records")
+            .match_body(mockito::Matcher::PartialJsonString(r#"{"type":"AAAA","name":"home","value":"2001:db8::7"}"#.into()))
+            .with_body(r#"{"record":{"id":"3","type":"AAAA","name":"home","value":"2001:db8::7","zone_id":"z1"}}"#)
+            .create();
+        cycle(Some("2001:db8::7"));
+        create.assert();
+        delete.assert();
+    }
+
+    #[test]
+    fn host_changes_lists_added_removed_and_changed_hosts() {
+        let host = |fqdn, ipv6, ttl| HostConfig { ttl, ..host(fqdn, ipv6) };
+        let old = [host("a.example.com", false, Some(300)), host("b.example.com", false, None)];
+        let new = [host("a.example.com", true, Some(600)), host("c.example.com", false, None)];
+
+        assert_eq!(host_changes(&old, &new), [
+            "a.example.com ttl 300 → 600",
+            "a.example.com ipv6 false → true",
+            "added c.example.com",
+            "removed b.example.com",
+        ]);
+        assert!(host_changes(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn provider_changes_lists_urls_and_consensus() {
+        let old = setup(Vec::new()).providers;
+        let new = Providers {
+            v4: vec!["https://a.example".into(), "https://b.example".into()],
+            v4_consensus: 2,
+            ..setup(Vec::new()).providers
+        };
+
+        assert_eq!(provider_changes(&old, &new), [
+            "ipv4_providers https://a.example, https://b.example",
+            "ipv4_consensus 1 → 2",
+        ]);
+        assert!(provider_changes(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn health_endpoint_reports_the_last_successful_cycle() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let last_success = Arc::new(AtomicU64::new(0));
+        serve_health(listener, Arc::clone(&last_success), 60);
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response.lines().next().unwrap_or_default().to_string()
+        };
+
+        assert_eq!(get("/healthz"), "HTTP/1.1 503 Service Unavailable");
+        last_success.store(unix_now(), Ordering::Relaxed);
+        assert_eq!(get("/healthz"), "HTTP/1.1 200 OK");
+        last_success.store(unix_now() - 61, Ordering::Relaxed);
+        assert_eq!(get("/healthz"), "HTTP/1.1 503 Service Unavailable");
+        assert_eq!(get("/metrics"), "HTTP/1.1 404 Not Found");
+    }
+
+    #[test]
+    fn read_token_file_trims_trailing_newline_and_rejects_empty_files() {
+        let path = std::env::temp_dir().join(format!("hetzner-ddns-token-{}", std::process::id()));
+        std::fs::write(&path, "secret-token \n").unwrap();
+        assert_eq!(read_token_file(&path).unwrap(), "secret-token");
+
+        std::fs::write(&path, "\n").unwrap();
+        let err = read_token_file(&path).unwrap_err().to_string();
+        assert!(err.contains("is empty"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cli_definition_is_valid() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+
+        let args = Cli::try_parse_from(["hetzner-ddns", "status", "--fqdn", "home.example.com", "--ipv6"]).unwrap();
+        assert_eq!(args.command, Some(Action::Status));
+        assert!(args.ipv6);
+        assert_eq!(Cli::try_parse_from(["hetzner-ddns", "--fqdn", "home.example.com"]).unwrap().command, None);
+    }
+
+    #[test]
+    fn update_cloud_rdns_needs_a_single_host() {
+        let args = Cli::try_parse_from(["hetzner-ddns", "--update-cloud-rdns", "--server-id", "42"]).unwrap();
+        assert!(check_rdns_hosts(&args, &[host("home.example.com", false)]).is_ok());
+        assert!(check_rdns_hosts(&args, &[host("home.example.com", false), host("nas.example.com", false)]).is_err());
+        assert!(check_rdns_hosts(&Cli::try_parse_from(["hetzner-ddns"]).unwrap(), &[]).is_ok());
+    }
+
+    #[test]
+    fn state_file_path_is_optional() {
+        let state_file = |extra: &[&str]| Cli::try_parse_from([&["hetzner-ddns", "--fqdn", "home.example.com"], extra].concat()).unwrap().state_file;
+        assert_eq!(state_file(&[]), None);
+        assert_eq!(state_file(&["--state-file"]), Some(None));
+        assert_eq!(state_file(&["--state-file", "/tmp/state.json"]), Some(Some(PathBuf::from("/tmp/state.json"))));
+        if let Ok(path) = default_state_file() {
+            assert!(path.ends_with("hetzner-ddns/state.json"));
+        }
+    }
+
+    #[test]
+    fn status_rows_flag_drift_and_missing_records() {
+        let a = Record { ttl: Some(300), ..record("1", "home", "192.0.2.9") };
+        let rows = [
+            StatusRow::new("home.example.com", "A", Some(&a), Some("192.0.2.9")),
+            StatusRow::new("home.example.com", "AAAA", None, Some("2001:db8::9")),
+            StatusRow::new("nas.example.com", "AAAA", None, None),
+        ];
+        assert_eq!(rows.iter().map(|r| r.drift).collect::<Vec<_>>(), [false, true, false]);
+
+        assert_eq!(status_table(&rows), [
+            "FQDN              TYPE  CURRENT    DETECTED     TTL      DRIFT",
+            "home.example.com  A     192.0.2.9  192.0.2.9    300      no",
+            "home.example.com  AAAA  (missing)  2001:db8::9  default  yes",
+            "nas.example.com   AAAA  (missing)  -            default  no",
+        ]);
+    }
+
+    #[test]
+    fn run_report_serializes_to_stable_schema() {
+        let report = RunReport {
+            ipv4: Some("192.0.2.9".into()),
+            ipv6: None,
+            records: vec![
+                RecordReport::new("home.example.com", "A", Some("192.0.2.1"), Some("192.0.2.9"), RecordAction::Updated)
+                    .with_id("r1"),
+                RecordReport::new("nas.example.com", "A", None, Some("192.0.2.9"), RecordAction::Created)
+                    .failed(&"❌ Creating A record nas failed"),
+            ],
+            ..RunReport::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            concat!(
+                r#"{"ipv4":"192.0.2.9","ipv6":null,"records":["#,
+                r#"{"fqdn":"home.example.com","type":"A","id":"r1","previous":"192.0.2.1","new":"192.0.2.9","action":"updated"},"#,
+                r#"{"fqdn":"nas.example.com","type":"A","id":null,"previous":null,"new":"192.0.2.9","action":"failed","error":"❌ Creating A record nas failed"}]}"#,
+            ),
+        );
+    }
+
+    #[test]
+    fn unfinished_cycles_report_every_host() {
+        let args = Cli::try_parse_from(["hetzner-ddns"]).unwrap();
+        let setup = setup(vec![host("home.example.com", true), host("nas.example.com", false)]);
+
+        // Detection failed: nothing is known but the hostnames.
+        let mut outcome = CycleOutcome::default();
+        report_unfinished(&args, &setup, &mut outcome, &Err("❌ Could not detect IPv4".into()));
+        let entries: Vec<_> = outcome.changes.report.iter()
+            .map(|r| (r.fqdn.as_str(), r.record_type.as_str(), r.action, r.error.as_deref()))
+            .collect();
+        assert_eq!(entries, [
+            ("home.example.com", "A", RecordAction::Failed, Some("❌ Could not detect IPv4")),
+            ("home.example.com", "AAAA", RecordAction::Failed, Some("❌ Could not detect IPv4")),
+            ("nas.example.com", "A", RecordAction::Failed, Some("❌ Could not detect IPv4")),
+        ]);
+
+        // A skipped run keeps what was already reported.
+        let mut outcome = CycleOutcome {
+            source: Some(ValueSource::Detected { ip4: "192.0.2.9".into(), ip6: None }),
+            skipped: Some("guard_cmd"),
+            ..CycleOutcome::default()
+        };
+        outcome.changes.report.push(RecordReport::new("home.example.com", "A", Some("192.0.2.9"), Some("192.0.2.9"), RecordAction::Unchanged));
+        report_unfinished(&args, &setup, &mut outcome, &Ok(0));
+        assert_eq!(outcome.changes.report[1], RecordReport::new("nas.example.com", "A", None, Some("192.0.2.9"), RecordAction::Skipped));
+        assert_eq!(outcome.changes.report.len(), 2);
+    }
+
+    #[test]
+    fn terraform_import_names_resources_after_the_fqdn() {
+        let record = |id, name| record(id, name, "192.0.2.9");
+
+        assert_eq!(
+            terraform_import_block(&record("r1", "home"), "example.com"),
+            "import {\n  to = hetznerdns_record.home_example_com_a\n  id = \"r1\"\n}",
+        );
+        assert!(terraform_import_block(&record("r2", "@"), "example.com").contains("hetznerdns_record.example_com_a\n"));
+        assert!(terraform_import_block(&record("r3", "@"), "1example.de").contains("hetznerdns_record.record_1example_de_a\n"));
+    }
+
+    #[test]
+    fn parse_ttl_enforces_bounds() {
+        assert_eq!(parse_ttl("120"), Ok(120));
+        assert_eq!(parse_ttl("60"), Ok(60));
+        assert!(parse_ttl("30").is_err());
+        assert!(parse_ttl("999999").is_err());
+        assert!(parse_ttl("soon").is_err());
+    }
+
+    #[test]
+    fn log_level_follows_verbose_and_quiet() {
+        assert_eq!(log_level(0, false), LevelFilter::Info);
+        assert_eq!(log_level(1, false), LevelFilter::Debug);
+        assert_eq!(log_level(3, false), LevelFilter::Trace);
+        assert_eq!(log_level(0, true), LevelFilter::Error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn syslog_filter_keeps_other_crates_at_warn() {
+        let passes = |filter: &env_filter::Filter, target: &str, level: log::Level| {
+            filter.enabled(&log::Metadata::builder().target(target).level(level).build())
+        };
+
+        let filter = syslog_filter(LevelFilter::Debug, None);
+        assert!(passes(&filter, "hetzner_ddns::sync", log::Level::Debug));
+        assert!(!passes(&filter, "hyper_util::client", log::Level::Debug));
+        assert!(passes(&filter, "hyper_util::client", log::Level::Warn));
+
+        let filter = syslog_filter(LevelFilter::Info, Some("hyper_util=trace"));
+        assert!(passes(&filter, "hyper_util::client", log::Level::Trace));
+    }
+
+    #[test]
+    fn txt_value_splits_long_values_into_quoted_chunks() {
+        let long = "k".repeat(300);
+        assert_eq!(txt_value(&long).unwrap(), format!("\"{}\" \"{}\"", "k".repeat(255), "k".repeat(45)));
+        assert_eq!(txt_value("say \"hi\"").unwrap(), "\"say \\\"hi\\\"\"");
+        assert_eq!(txt_value("\"v=spf1 -all\"").unwrap(), "\"v=spf1 -all\"");
+    }
+
+    #[test]
+    fn parse_replay_reads_one_cycle_per_line() {
+        let entries = parse_replay("# flapping IPv6\n203.0.113.7 2001:db8::1\n\n203.0.113.7\n198.51.100.2 2001:db8::2 # back\n").unwrap();
+        let detected = |ip4: &str, ip6: Option<&str>| ValueSource::Detected { ip4: ip4.to_string(), ip6: ip6.map(str::to_string) };
+        assert_eq!(entries, [
+            detected("203.0.113.7", Some("2001:db8::1")),
+            detected("203.0.113.7", None),
+            detected("198.51.100.2", Some("2001:db8::2")),
+        ]);
+
+        assert_eq!(parse_replay("203.0.113.7\n2001:db8::1\n").unwrap_err(), "line 2: expected an IPv4 address, optionally followed by an IPv6 address");
+    }
+
+    #[test]
+    fn parse_config_minimal() {
+        let config = parse_config("[[hosts]]\nfqdn = \"home.example.com\"\n").unwrap();
+
+        assert_eq!(config.api_token, None);
+        assert_eq!(config.ipv4_providers, None);
+        assert_eq!(config.hosts, vec![host("home.example.com", false)]);
+    }
+
+    #[test]
+    fn parse_config_multiple_hosts() {
+        let config = parse_config(r#"
+            api_token = "secret"
+            ipv6_providers = ["https://v6.example.net", "https://v6.example.org"]
+            ipv6_consensus = 2
+
+            [[hosts]]
+            fqdn = "home.example.com"
+            ipv6 = true
+
+            [[hosts]]
+            fqdn = "vpn.otherdomain.de"
+            ttl = 300
+        "#).unwrap();
+
+        assert_eq!(config.api_token.as_deref(), Some("secret"));
+        assert_eq!(config.ipv4_providers, None);
+        assert_eq!(config.ipv6_providers.unwrap(), ["https://v6.example.net", "https://v6.example.org"]);
+        assert_eq!((config.ipv4_consensus, config.ipv6_consensus), (None, Some(2)));
+        assert_eq!(config.hosts, vec![
+            host("home.example.com", true),
+            HostConfig { ttl: Some(300), ..host("vpn.otherdomain.de", false) },
+        ]);
+    }
+
+    #[test]
+    fn parse_config_rejects_unknown_key() {
+        let err = parse_config("[[hosts]]\nfqdn = \"home.example.com\"\ntll = 300\n").unwrap_err();
+
+        assert!(err.contains("tll"), "{}", err);
+    }
+
+    #[test]
+    fn parse_config_names_missing_key() {
+        let err = parse_config("[[hosts]]\nipv6 = true\n").unwrap_err();
+
+        assert!(err.contains("fqdn"), "{}", err);
+    }
+
+    #[test]
+    fn given_address_rejects_garbage_and_private_ranges() {
+        assert_eq!(given_address("--ipv4-address", " 203.0.114.7 ", false, is_public_ipv4).unwrap(), "203.0.114.7".parse::<Ipv4Addr>().unwrap());
+        assert!(given_address::<Ipv4Addr>("--ipv4-address", "300.1.1.1", false, is_public_ipv4).is_err());
+        assert!(given_address::<Ipv4Addr>("--ipv4-address", "2a01:4f8::1", false, is_public_ipv4).is_err());
+        assert!(given_address::<Ipv4Addr>("--ipv4-address", "192.168.1.10", false, is_public_ipv4).is_err());
+        assert!(given_address::<Ipv4Addr>("--ipv4-address", "192.168.1.10", true, is_public_ipv4).is_ok());
+        assert!(given_address::<Ipv6Addr>("--ipv6-address", "2a01:4f8::1", false, is_public_ipv6).is_ok());
+        assert!(given_address::<Ipv6Addr>("--ipv6-address", "2001:db8::1", false, is_public_ipv6).is_err());
+        assert!(given_address::<Ipv6Addr>("--ipv6-address", "fe80::1", false, is_public_ipv6).is_err());
+    }
+}
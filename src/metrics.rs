@@ -0,0 +1,247 @@
+//! Run counters for --metrics-file, in the Prometheus text format.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use log::warn;
+
+use crate::state::write_atomically;
+use crate::sync::{RecordAction, RecordReport, ValueSource};
+use crate::{unix_now, ApiUsage};
+
+/// Counters for --metrics-file. They start from the existing file and add
+/// up across runs and daemon iterations.
+#[derive(Default)]
+pub struct Metrics {
+    pub last_run: u64,
+    pub last_success: u64,
+    /// Records handled, by record type and result.
+    pub updates: BTreeMap<(String, &'static str), u64>,
+    /// Unix time a record of each type last changed.
+    pub last_change: BTreeMap<String, u64>,
+    /// Requests sent to the Hetzner DNS API.
+    pub api_reads: u64,
+    pub api_writes: u64,
+    pub ipv4: Option<String>,
+    pub ipv6: Option<String>,
+}
+
+impl Metrics {
+    /// Picks up the counters and timestamps of an existing metrics file, so
+    /// one-shot runs keep adding to them. A missing or unreadable file starts
+    /// from zero.
+    pub fn load(path: &Path) -> Metrics {
+        let mut metrics = Metrics::default();
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return metrics,
+            Err(e) => {
+                warn!("⚠️ Could not read metrics file {}, starting from zero: {}", path.display(), e);
+                return metrics;
+            }
+        };
+        for line in text.lines().filter(|l| !l.starts_with('#')) {
+            let Some((series, value)) = line.rsplit_once(' ') else { continue };
+            let Ok(value) = value.parse::<u64>() else { continue };
+            let (name, labels) = match series.split_once('{') {
+                Some((name, labels)) => (name, parse_labels(labels.trim_end_matches('}'))),
+                None => (series, Vec::new()),
+            };
+            let label = |key: &str| labels.iter().find(|(k, _)| *k == key).map(|(_, v)| v.clone());
+            match name {
+                "hetzner_ddns_last_success_timestamp_seconds" => metrics.last_success = value,
+                "hetzner_ddns_last_run_timestamp_seconds" => metrics.last_run = value,
+                "hetzner_ddns_update_total" => {
+                    let result = ["updated", "unchanged", "failed", "deleted"].into_iter()
+                        .find(|r| label("result").as_deref() == Some(*r));
+                    if let (Some(record_type), Some(result)) = (label("record_type"), result) {
+                        metrics.updates.insert((record_type, result), value);
+                    }
+                }
+                "hetzner_ddns_last_change_timestamp_seconds" => {
+                    if let Some(record_type) = label("record_type") {
+                        metrics.last_change.insert(record_type, value);
+                    }
+                }
+                "hetzner_ddns_api_requests_total" => match label("kind").as_deref() {
+                    Some("read") => metrics.api_reads = value,
+                    Some("write") => metrics.api_writes = value,
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        metrics
+    }
+
+    /// Counts the records of a run that ended at `now` and remembers the
+    /// detected addresses.
+    pub fn observe(&mut self, source: Option<&ValueSource>, report: &[RecordReport], now: u64) {
+        if let Some(ValueSource::Detected { ip4, ip6 }) = source {
+            self.ipv4 = Some(ip4.clone());
+            self.ipv6 = ip6.clone();
+        }
+        for entry in report {
+            let result = match entry.action {
+                RecordAction::Updated | RecordAction::Created => "updated",
+                RecordAction::Unchanged => "unchanged",
+                RecordAction::Missing | RecordAction::Failed | RecordAction::NotVisible => "failed",
+                RecordAction::Deleted => "deleted",
+                RecordAction::WouldUpdate | RecordAction::WouldCreate | RecordAction::WouldDelete | RecordAction::Skipped => continue,
+            };
+            *self.updates.entry((entry.record_type.clone(), result)).or_default() += 1;
+            if matches!(entry.action, RecordAction::Updated | RecordAction::Created | RecordAction::Deleted) {
+                self.last_change.insert(entry.record_type.clone(), now);
+            }
+        }
+    }
+
+    /// Notes the end of a run and rewrites the metrics file.
+    pub fn finish(&mut self, path: &Path, success: bool, usage: &ApiUsage) {
+        self.last_run = unix_now();
+        self.api_reads += u64::from(usage.reads);
+        self.api_writes += u64::from(usage.writes);
+        if success {
+            self.last_success = self.last_run;
+        }
+        if let Err(e) = write_atomically(path, &self.render()) {
+            warn!("⚠️ Could not write metrics file {}: {}", path.display(), e);
+        }
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP hetzner_ddns_last_run_timestamp_seconds Unix time the last run finished.\n");
+        out.push_str("# TYPE hetzner_ddns_last_run_timestamp_seconds gauge\n");
+        out.push_str(&format!("hetzner_ddns_last_run_timestamp_seconds {}\n", self.last_run));
+        out.push_str("# HELP hetzner_ddns_last_success_timestamp_seconds Unix time the last successful run finished.\n");
+        out.push_str("# TYPE hetzner_ddns_last_success_timestamp_seconds gauge\n");
+        out.push_str(&format!("hetzner_ddns_last_success_timestamp_seconds {}\n", self.last_success));
+        out.push_str("# HELP hetzner_ddns_update_total Records handled, by record type and result.\n");
+        out.push_str("# TYPE hetzner_ddns_update_total counter\n");
+        for ((record_type, result), count) in &self.updates {
+            out.push_str(&format!(
+                "hetzner_ddns_update_total{{record_type=\"{}\",result=\"{}\"}} {}\n",
+                label_value(record_type), result, count
+            ));
+        }
+        if !self.last_change.is_empty() {
+            out.push_str("# HELP hetzner_ddns_last_change_timestamp_seconds Unix time a record of this type last changed.\n");
+            out.push_str("# TYPE hetzner_ddns_last_change_timestamp_seconds gauge\n");
+            for (record_type, time) in &self.last_change {
+                out.push_str(&format!(
+                    "hetzner_ddns_last_change_timestamp_seconds{{record_type=\"{}\"}} {}\n",
+                    label_value(record_type), time
+                ));
+            }
+        }
+        out.push_str("# HELP hetzner_ddns_api_requests_total Requests sent to the Hetzner DNS API, by kind.\n");
+        out.push_str("# TYPE hetzner_ddns_api_requests_total counter\n");
+        out.push_str(&format!("hetzner_ddns_api_requests_total{{kind=\"read\"}} {}\n", self.api_reads));
+        out.push_str(&format!("hetzner_ddns_api_requests_total{{kind=\"write\"}} {}\n", self.api_writes));
+        if let Some(ipv4) = &self.ipv4 {
+            out.push_str("# HELP hetzner_ddns_detected_ip_info Public addresses detected by the last run.\n");
+            out.push_str("# TYPE hetzner_ddns_detected_ip_info gauge\n");
+            out.push_str(&format!(
+                "hetzner_ddns_detected_ip_info{{ipv4=\"{}\",ipv6=\"{}\"}} 1\n",
+                label_value(ipv4), label_value(self.ipv6.as_deref().unwrap_or(""))
+            ));
+        }
+        out
+    }
+}
+
+/// Escapes a Prometheus label value.
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Splits `key="value",...` into its pairs, undoing `label_value`.
+fn parse_labels(labels: &str) -> Vec<(&str, String)> {
+    let mut pairs = Vec::new();
+    let mut rest = labels;
+    while let Some((key, after)) = rest.split_once("=\"") {
+        let mut value = String::new();
+        let mut chars = after.char_indices();
+        let mut end = after.len();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, c)) => value.push(c),
+                    None => {}
+                },
+                '"' => {
+                    end = i + 1;
+                    break;
+                }
+                c => value.push(c),
+            }
+        }
+        pairs.push((key.trim_start_matches(','), value));
+        rest = &after[end..];
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_count_across_runs_and_render_as_text_format() {
+        let source = ValueSource::Detected { ip4: "192.0.2.9".into(), ip6: None };
+        let mut metrics = Metrics::default();
+        metrics.observe(Some(&source), &[
+            RecordReport::new("home.example.com", "A", Some("192.0.2.1"), Some("192.0.2.9"), RecordAction::Updated),
+            RecordReport::new("nas.example.com", "A", None, Some("192.0.2.9"), RecordAction::Missing),
+        ], 50);
+        metrics.observe(Some(&source), &[
+            RecordReport::new("home.example.com", "A", Some("192.0.2.9"), Some("192.0.2.9"), RecordAction::Unchanged),
+            RecordReport::new("nas.example.com", "A", None, Some("192.0.2.9"), RecordAction::Created),
+        ], 150);
+        metrics.observe(Some(&source), &[
+            RecordReport::new("home.example.com", "A", Some("192.0.2.9"), Some("192.0.2.9"), RecordAction::Unchanged),
+        ], 200);
+        metrics.last_run = 200;
+        metrics.last_success = 100;
+        metrics.api_reads = 7;
+        metrics.api_writes = 2;
+
+        let text = metrics.render();
+        assert!(text.contains("hetzner_ddns_last_run_timestamp_seconds 200\n"));
+        assert!(text.contains("hetzner_ddns_last_success_timestamp_seconds 100\n"));
+        assert!(text.contains("hetzner_ddns_update_total{record_type=\"A\",result=\"updated\"} 2\n"));
+        assert!(text.contains("hetzner_ddns_update_total{record_type=\"A\",result=\"unchanged\"} 2\n"));
+        assert!(text.contains("hetzner_ddns_update_total{record_type=\"A\",result=\"failed\"} 1\n"));
+        assert!(text.contains("hetzner_ddns_last_change_timestamp_seconds{record_type=\"A\"} 150\n"));
+        assert!(!text.contains("record_type=\"AAAA\""));
+        assert!(text.contains("hetzner_ddns_api_requests_total{kind=\"read\"} 7\n"));
+        assert!(text.contains("hetzner_ddns_api_requests_total{kind=\"write\"} 2\n"));
+        assert!(text.contains("hetzner_ddns_detected_ip_info{ipv4=\"192.0.2.9\",ipv6=\"\"} 1\n"));
+    }
+
+    #[test]
+    fn metrics_file_is_picked_up_again() {
+        let path = std::env::temp_dir().join(format!("hetzner-ddns-metrics-{}.prom", std::process::id()));
+        let mut metrics = Metrics::default();
+        metrics.observe(None, &[
+            RecordReport::new("home.example.com", "AAAA", Some("2001:db8::1"), Some("2001:db8::9"), RecordAction::Updated),
+        ], 150);
+        metrics.finish(&path, true, &ApiUsage { reads: 3, writes: 1 });
+        let last_success = metrics.last_success;
+
+        // A later failing run keeps the last success and change, and adds up.
+        let mut metrics = Metrics::load(&path);
+        metrics.finish(&path, false, &ApiUsage { reads: 2, writes: 0 });
+        let metrics = Metrics::load(&path);
+        assert_eq!(metrics.last_success, last_success);
+        assert_eq!(metrics.last_change["AAAA"], 150);
+        assert_eq!(metrics.updates[&("AAAA".to_string(), "updated")], 1);
+        assert_eq!((metrics.api_reads, metrics.api_writes), (5, 1));
+        assert_eq!(parse_labels(&format!("a=\"{}\",b=\"x\"", label_value("q\"\\"))), [("a", "q\"\\".to_string()), ("b", "x".to_string())]);
+
+        assert_eq!(Metrics::load(&path.with_extension("missing")).last_success, 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
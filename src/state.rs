@@ -0,0 +1,160 @@
+//! The --state-file: what each record held after the last run, so runs
+//! whose addresses didn't change can skip the Hetzner API.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::sync::{RecordAction, RecordReport};
+
+/// The values last written to (or found in) each record, per hostname and
+/// record type, kept between runs in the --state-file.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct State {
+    #[serde(default)]
+    pub records: BTreeMap<String, BTreeMap<String, CachedValue>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CachedValue {
+    pub value: String,
+    /// The configured TTL, once a run made sure the record has it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+    /// Unix time the value was written or first seen.
+    pub updated_at: u64,
+}
+
+impl State {
+    /// Reads the state file. A missing or unreadable file is a cold start.
+    pub fn load(path: &Path) -> State {
+        let json = match std::fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return State::default(),
+            Err(e) => {
+                warn!("⚠️ Could not read state file {}, ignoring it: {}", path.display(), e);
+                return State::default();
+            }
+        };
+        serde_json::from_str(&json).unwrap_or_else(|e| {
+            warn!("⚠️ State file {} is corrupt, ignoring it: {}", path.display(), e);
+            State::default()
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        write_atomically(path, &serde_json::to_string_pretty(self)?)
+    }
+
+    /// Whether every wanted `(record type, value)` of `fqdn` is cached, with
+    /// `ttl` if one has to be enforced (--update-ttl).
+    pub fn matches(&self, fqdn: &str, wanted: &[(&str, &str)], ttl: Option<u32>) -> bool {
+        let Some(cached) = self.records.get(fqdn) else {
+            return false;
+        };
+        !wanted.is_empty() && wanted.iter().all(|(record_type, value)| {
+            cached.get(*record_type).is_some_and(|c| c.value == *value && (ttl.is_none() || c.ttl == ttl))
+        })
+    }
+
+    pub fn has(&self, fqdn: &str, record_type: &str) -> bool {
+        self.records.get(fqdn).is_some_and(|cached| cached.contains_key(record_type))
+    }
+
+    /// Remembers the records a run wrote or found current, and forgets
+    /// deleted ones. `ttl_of` gives the configured TTL of a hostname, which
+    /// a record only has for sure once it was written or checked with
+    /// `update_ttl`.
+    pub fn record(&mut self, report: &[RecordReport], ttl_of: impl Fn(&str) -> Option<u32>, update_ttl: bool, now: u64) {
+        for entry in report {
+            if entry.action == RecordAction::Deleted {
+                if let Some(cached) = self.records.get_mut(&entry.fqdn) {
+                    cached.remove(&entry.record_type);
+                }
+                continue;
+            }
+            let written = matches!(entry.action, RecordAction::Updated | RecordAction::Created);
+            if !written && entry.action != RecordAction::Unchanged {
+                continue;
+            }
+            let Some(value) = &entry.new else { continue };
+            let ttl = if written || update_ttl { ttl_of(&entry.fqdn) } else { None };
+            let cached = self.records.entry(entry.fqdn.clone()).or_default();
+            match cached.get_mut(&entry.record_type) {
+                // Keep when the value was first seen.
+                Some(c) if !written && c.value == *value => {
+                    if ttl.is_some() {
+                        c.ttl = ttl;
+                    }
+                }
+                _ => {
+                    cached.insert(entry.record_type.clone(), CachedValue { value: value.clone(), ttl, updated_at: now });
+                }
+            }
+        }
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it into
+/// place, so readers never see a half-written file.
+pub fn write_atomically(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_remembers_written_and_current_records() {
+        let ttl_of = |fqdn: &str| (fqdn == "home.example.com").then_some(120);
+        let mut state = State::default();
+        state.record(&[
+            RecordReport::new("home.example.com", "A", Some("192.0.2.1"), Some("192.0.2.9"), RecordAction::Updated),
+            RecordReport::new("home.example.com", "AAAA", Some("2001:db8::9"), Some("2001:db8::9"), RecordAction::Unchanged),
+            RecordReport::new("nas.example.com", "A", None, Some("192.0.2.9"), RecordAction::Missing),
+        ], ttl_of, false, 100);
+        state.record(&[
+            RecordReport::new("home.example.com", "AAAA", Some("2001:db8::9"), Some("2001:db8::9"), RecordAction::Unchanged),
+        ], ttl_of, false, 200);
+
+        assert!(state.matches("home.example.com", &[("A", "192.0.2.9"), ("AAAA", "2001:db8::9")], None));
+        assert!(!state.matches("home.example.com", &[("A", "192.0.2.10")], None));
+        assert!(!state.matches("nas.example.com", &[("A", "192.0.2.9")], None));
+        assert_eq!(state.records["home.example.com"]["AAAA"].updated_at, 100);
+
+        // The unchanged AAAA record was never checked for its TTL.
+        assert!(state.matches("home.example.com", &[("A", "192.0.2.9")], Some(120)));
+        assert!(!state.matches("home.example.com", &[("AAAA", "2001:db8::9")], Some(120)));
+        state.record(&[
+            RecordReport::new("home.example.com", "AAAA", Some("2001:db8::9"), Some("2001:db8::9"), RecordAction::Unchanged),
+        ], ttl_of, true, 300);
+        assert!(state.matches("home.example.com", &[("AAAA", "2001:db8::9")], Some(120)));
+        assert_eq!(state.records["home.example.com"]["AAAA"].updated_at, 100);
+    }
+
+    #[test]
+    fn state_file_is_written_atomically_and_corruption_is_a_cold_start() {
+        let dir = std::env::temp_dir().join(format!("hetzner-ddns-state-{}", std::process::id()));
+        let path = dir.join("state.json");
+        let mut state = State::default();
+        state.record(&[RecordReport::new("home.example.com", "A", None, Some("192.0.2.9"), RecordAction::Created)], |_| None, false, 100);
+
+        state.save(&path).unwrap();
+        assert_eq!(State::load(&path), state);
+        assert!(!dir.join("state.json.tmp").exists());
+
+        std::fs::write(&path, "{not json").unwrap();
+        assert_eq!(State::load(&path), State::default());
+        assert_eq!(State::load(&dir.join("missing.json")), State::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
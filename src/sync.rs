@@ -0,0 +1,344 @@
+//! Deciding which records need a change, sending the changes to the
+//! Hetzner DNS API and reporting the outcome of every record.
+
+use log::{debug, error, info};
+use serde::Serialize;
+
+use crate::{failure_kind, find_record, is_auth_error, needs_update, Compare, FailureKind, HetznerDnsClient, NewRecord, Record};
+
+/// Where the new record value comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValueSource {
+    /// A value supplied by the user for the `--record-type` record.
+    Explicit(String),
+    /// Public addresses detected for the A and AAAA records.
+    Detected { ip4: String, ip6: Option<String> },
+}
+
+/// The outcome for one record of a run.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RecordReport {
+    pub fqdn: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    /// The Hetzner record id; None for records that don't exist (yet).
+    pub id: Option<String>,
+    pub previous: Option<String>,
+    pub new: Option<String>,
+    pub action: RecordAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RecordReport {
+    pub fn new(fqdn: &str, record_type: &str, previous: Option<&str>, new: Option<&str>, action: RecordAction) -> Self {
+        RecordReport {
+            fqdn: fqdn.to_string(),
+            record_type: record_type.to_string(),
+            id: None,
+            previous: previous.map(str::to_string),
+            new: new.map(str::to_string),
+            action,
+            error: None,
+        }
+    }
+
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    pub fn failed(mut self, error: &dyn std::fmt::Display) -> Self {
+        self.action = RecordAction::Failed;
+        self.error = Some(error.to_string());
+        self
+    }
+}
+
+/// The `would_*` actions are only reported under --dry-run, `not_visible`
+/// only with --verify.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordAction {
+    Updated,
+    Unchanged,
+    Created,
+    Missing,
+    Failed,
+    /// The API accepted the change but --verify didn't see it on the nameservers.
+    NotVisible,
+    /// Removed by --prune-missing-aaaa.
+    Deleted,
+    WouldUpdate,
+    WouldCreate,
+    WouldDelete,
+    /// Left alone because --guard-cmd or --skip-on-metered skipped the run.
+    Skipped,
+}
+
+/// A record change decided while syncing, sent once every host is done.
+pub struct PendingUpdate {
+    old: Record,
+    updated: Record,
+    label: String,
+    fqdn: String,
+}
+
+/// What a run touched: records for --terraform-import, updates to send and
+/// the outcome of every record for --output json.
+/// Under --dry-run nothing is sent and the changes are only counted.
+#[derive(Default)]
+pub struct Changes {
+    pub processed: Vec<Record>,
+    pub updates: Vec<PendingUpdate>,
+    pub report: Vec<RecordReport>,
+    pub dry_run: bool,
+    /// Delete AAAA records of hostnames without IPv6 (--prune-missing-aaaa,
+    /// once the loss is conclusive).
+    pub prune_aaaa: bool,
+    pub pending: u32,
+}
+
+/// Decides what an existing record of `fqdn` needs to hold `value`: an
+/// update if it differs under `compare` (always with `force`), else it is
+/// reported as current.
+pub fn plan_record(changes: &mut Changes, record: &Record, value: &str, ttl: Option<u32>, compare: Compare, force: bool, fqdn: &str) {
+    let label = format!("{} record {}", record.record_type, fqdn);
+    changes.processed.push(record.clone());
+    if force || needs_update(record, value, ttl, compare) {
+        plan_update(changes, record, value, ttl, &label, fqdn);
+    } else {
+        report_current(changes, record, fqdn, &label);
+    }
+}
+
+/// Plans setting the record to `new_value` and reports it. `label` names the
+/// record in log lines, e.g. "A record".
+fn plan_update(changes: &mut Changes, record: &Record, new_value: &str, ttl: Option<u32>, label: &str, fqdn: &str) {
+    // Without a configured TTL the record keeps the one it has.
+    let ttl = ttl.or(record.ttl);
+    if changes.dry_run {
+        info!("WOULD update {} {}: {} -> {} (ttl {})", record.record_type, fqdn, record.value, new_value, format_ttl(ttl));
+        changes.report.push(RecordReport::new(fqdn, &record.record_type, Some(&record.value), Some(new_value), RecordAction::WouldUpdate).with_id(&record.id));
+        changes.pending += 1;
+        return;
+    }
+    if record.value == new_value && record.ttl == ttl {
+        info!("🔄 Rewriting {} with {} (--force)", label, new_value);
+    } else if record.value == new_value {
+        info!("🔄 Updating {} TTL from {} to {}", label, format_ttl(record.ttl), format_ttl(ttl));
+    } else {
+        info!("🔄 Updating {} from {} to {}", label, record.value, new_value);
+    }
+    changes.updates.push(PendingUpdate {
+        old: record.clone(),
+        updated: Record {
+            value: new_value.to_string(),
+            ttl,
+            ..record.to_owned()
+        },
+        label: label.to_string(),
+        fqdn: fqdn.to_string(),
+    });
+}
+
+/// Reports a record that already holds the wanted value.
+fn report_current(changes: &mut Changes, record: &Record, fqdn: &str, label: &str) {
+    changes.report.push(RecordReport::new(fqdn, &record.record_type, Some(&record.value), Some(&record.value), RecordAction::Unchanged).with_id(&record.id));
+    if changes.dry_run {
+        info!("no change {} {}: {}", record.record_type, fqdn, record.value);
+    } else {
+        debug!("✅ {} already up to date: {}", label, record.value);
+    }
+}
+
+/// Sends the planned updates: a plain PUT for a single record, otherwise one
+/// bulk request so A and AAAA move together. The outcome of each record is
+/// added to `report`; a rejected token is returned as an error. Returns how
+/// bad the failure was for each hostname with a failed record.
+pub fn apply_updates(
+    api: &mut HetznerDnsClient,
+    updates: Vec<PendingUpdate>,
+    report: &mut Vec<RecordReport>,
+) -> Result<Vec<FailureKind>, Box<dyn std::error::Error>> {
+    // Record ids that failed, with the reason and how bad it was.
+    let failed: Vec<(String, String, FailureKind)> = match updates.as_slice() {
+        [] => return Ok(Vec::new()),
+        [update] => match api.update_record(&update.updated) {
+            Ok(()) => Vec::new(),
+            Err(e) if is_auth_error(&*e) => return Err(e),
+            Err(e) => {
+                error!("{}", e);
+                vec![(update.updated.id.clone(), e.to_string(), failure_kind(&*e))]
+            }
+        },
+        _ => {
+            let records: Vec<Record> = updates.iter().map(|u| u.updated.clone()).collect();
+            match api.update_records(&records) {
+                // Anything the API didn't confirm counts as failed.
+                Ok(response) => records.iter()
+                    .filter(|r| response.failed_records.iter().any(|f| f.id == r.id)
+                        || !response.records.iter().any(|v| v.id == r.id))
+                    .map(|r| (r.id.clone(), "rejected by the bulk update".to_string(), FailureKind::Other))
+                    .collect(),
+                Err(e) if is_auth_error(&*e) => return Err(e),
+                Err(e) => {
+                    error!("{}", e);
+                    let kind = failure_kind(&*e);
+                    records.iter().map(|r| (r.id.clone(), e.to_string(), kind)).collect()
+                }
+            }
+        }
+    };
+
+    let mut failed_hosts: Vec<(&str, FailureKind)> = Vec::new();
+    for update in &updates {
+        let entry = RecordReport::new(
+            &update.fqdn,
+            &update.old.record_type,
+            Some(&update.old.value),
+            Some(&update.updated.value),
+            RecordAction::Updated,
+        ).with_id(&update.old.id);
+        if let Some((_, reason, kind)) = failed.iter().find(|(id, _, _)| *id == update.updated.id) {
+            error!("❌ {} could not be updated.", update.label);
+            report.push(entry.failed(reason));
+            match failed_hosts.iter_mut().find(|(fqdn, _)| *fqdn == update.fqdn) {
+                Some((_, worst)) => *worst = (*worst).max(*kind),
+                None => failed_hosts.push((&update.fqdn, *kind)),
+            }
+            continue;
+        }
+        info!("✅ {} updated.", update.label);
+        report.push(entry);
+    }
+    Ok(failed_hosts.into_iter().map(|(_, kind)| kind).collect())
+}
+
+/// Creates a missing record and reports it.
+pub fn create_record(
+    api: &mut HetznerDnsClient,
+    changes: &mut Changes,
+    record: NewRecord,
+    fqdn: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if changes.dry_run {
+        info!("WOULD create {} {}: {} (ttl {})", record.record_type, fqdn, record.value, format_ttl(record.ttl));
+        changes.report.push(RecordReport::new(fqdn, &record.record_type, None, Some(&record.value), RecordAction::WouldCreate));
+        changes.pending += 1;
+        return Ok(());
+    }
+    let entry = RecordReport::new(fqdn, &record.record_type, None, Some(&record.value), RecordAction::Created);
+    let created = match api.create_record(&record) {
+        Ok(created) => created,
+        Err(e) => {
+            changes.report.push(entry.failed(&e));
+            return Err(e);
+        }
+    };
+    info!("➕ Created {} record {} -> {}", created.record_type, fqdn, created.value);
+    changes.report.push(entry.with_id(&created.id));
+    changes.processed.push(created);
+    Ok(())
+}
+
+/// Deletes the AAAA record `name` of a hostname that has no public IPv6
+/// address anymore (--prune-missing-aaaa).
+pub fn prune_aaaa(
+    api: &mut HetznerDnsClient,
+    changes: &mut Changes,
+    records: &[Record],
+    name: &str,
+    zone_id: &str,
+    fqdn: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(record) = find_record(records, name, "AAAA", zone_id) else {
+        debug!("No public IPv6 address and no AAAA record for {} to remove.", fqdn);
+        return Ok(());
+    };
+    if changes.dry_run {
+        info!("WOULD delete AAAA {}: {}", fqdn, record.value);
+        changes.report.push(RecordReport::new(fqdn, "AAAA", Some(&record.value), None, RecordAction::WouldDelete).with_id(&record.id));
+        changes.pending += 1;
+        return Ok(());
+    }
+
+    let entry = RecordReport::new(fqdn, "AAAA", Some(&record.value), None, RecordAction::Deleted).with_id(&record.id);
+    if let Err(e) = api.delete_record(record) {
+        changes.report.push(entry.failed(&e));
+        return Err(e);
+    }
+    info!("🗑️ Deleted AAAA record {} ({}): no public IPv6 address found.", fqdn, record.value);
+    changes.report.push(entry);
+    Ok(())
+}
+
+pub fn format_ttl(ttl: Option<u32>) -> String {
+    ttl.map(|t| t.to_string()).unwrap_or_else(|| "default".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::api;
+
+    /// An update of record `id` of home.example.com to `value`.
+    fn pending(id: &str, record_type: &str, value: &str) -> PendingUpdate {
+        let old = Record {
+            id: id.to_string(),
+            record_type: record_type.to_string(),
+            name: "home".to_string(),
+            value: "192.0.2.1".to_string(),
+            zone_id: "z1".to_string(),
+            ttl: Some(60),
+        };
+        PendingUpdate {
+            label: format!("{} record home.example.com", record_type),
+            updated: Record { value: value.to_string(), ..old.clone() },
+            old,
+            fqdn: "home.example.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn apply_updates_sends_one_bulk_request_and_reports_failures() {
+        let mut server = mockito::Server::new();
+        let bulk = server.mock("PUT", "/records/bulk")
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"records":[{"id":"1","value":"192.0.2.9"},{"id":"2","value":"2001:db8::9"}]}"#.to_string(),
+            ))
+            .with_body(r#"{"records":[{"id":"1","type":"A","name":"home","value":"192.0.2.9","zone_id":"z1"}],"failed_records":[{"id":"2"}]}"#)
+            .create();
+        let single = server.mock("PUT", mockito::Matcher::Regex("^/records/[^/]+$".into()))
+            .expect(0)
+            .create();
+
+        let updates = vec![
+            pending("1", "A", "192.0.2.9"),
+            pending("2", "AAAA", "2001:db8::9"),
+        ];
+        let mut report = Vec::new();
+        let failed = apply_updates(&mut api(server.url()), updates, &mut report).unwrap();
+
+        bulk.assert();
+        single.assert();
+        assert_eq!(failed, [FailureKind::Other]);
+        let actions: Vec<RecordAction> = report.iter().map(|r| r.action).collect();
+        assert_eq!(actions, [RecordAction::Updated, RecordAction::Failed]);
+        assert!(report[1].error.is_some());
+    }
+
+    #[test]
+    fn apply_updates_uses_single_put_for_one_record() {
+        let mut server = mockito::Server::new();
+        let single = server.mock("PUT", "/records/1")
+            .with_body(r#"{"record":{"id":"1","type":"A","name":"home","value":"192.0.2.9","zone_id":"z1"}}"#)
+            .create();
+
+        let updates = vec![pending("1", "A", "192.0.2.9")];
+        let failed = apply_updates(&mut api(server.url()), updates, &mut Vec::new()).unwrap();
+
+        single.assert();
+        assert!(failed.is_empty());
+    }
+}
@@ -0,0 +1,209 @@
+//! Checking that the authoritative nameservers serve what a run wrote
+//! (--verify), and whether they sign a zone with DNSSEC.
+
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use log::{debug, error, info};
+
+use crate::sync::{RecordAction, RecordReport};
+use crate::{split_fqdn, Shutdown, Zone};
+
+/// Hetzner's authoritative nameservers, for zones the API lists none for.
+const HETZNER_NAMESERVERS: &[&str] = &["hydrogen.ns.hetzner.com", "oxygen.ns.hetzner.com", "helium.ns.hetzner.de"];
+/// Pause between --verify rounds.
+const VERIFY_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a single nameserver may take to answer.
+const DNS_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Checks that the authoritative nameservers serve every A/AAAA record this
+/// run updated or created, polling until `timeout`. Records that don't show
+/// up are marked not visible. Returns how many hostnames had such a record.
+pub fn verify_changes(report: &mut [RecordReport], zones: &[Zone], timeout: Duration, shutdown: &Shutdown) -> usize {
+    // A --verify-timeout too large to add up means waiting for good.
+    let deadline = Instant::now().checked_add(timeout);
+    let mut failed_hosts: Vec<String> = Vec::new();
+
+    for entry in report.iter_mut() {
+        if !matches!(entry.action, RecordAction::Updated | RecordAction::Created)
+            || !matches!(entry.record_type.as_str(), "A" | "AAAA") {
+            continue;
+        }
+        let Some(value) = entry.new.clone() else { continue };
+        let nameservers = nameservers(split_fqdn(&entry.fqdn, zones).map_or(&[][..], |(_, zone)| &zone.ns));
+
+        match wait_until_visible(&nameservers, &entry.fqdn, &entry.record_type, &value, deadline, shutdown) {
+            Ok(()) => info!("🔍 {} record {} is served by the nameservers.", entry.record_type, entry.fqdn),
+            Err(e) => {
+                let e = format!("❌ {} record {} was accepted but is not visible yet: {}", entry.record_type, entry.fqdn, e);
+                error!("{}", e);
+                entry.action = RecordAction::NotVisible;
+                entry.error = Some(e);
+                if !failed_hosts.contains(&entry.fqdn) {
+                    failed_hosts.push(entry.fqdn.clone());
+                }
+            }
+        }
+    }
+    failed_hosts.len()
+}
+
+/// The nameservers the API lists for a zone, or Hetzner's if it lists none.
+fn nameservers(listed: &[String]) -> Vec<String> {
+    if listed.is_empty() {
+        HETZNER_NAMESERVERS.iter().map(|ns| ns.to_string()).collect()
+    } else {
+        listed.to_vec()
+    }
+}
+
+/// Whether the nameservers of `zone` publish DNSKEY records for it, i.e. sign
+/// it with DNSSEC. The first nameserver that answers decides.
+pub fn zone_is_signed(zone: &Zone) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut last_error = None;
+    for ns in nameservers(&zone.ns) {
+        match (ns.as_str(), 53).to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => match signed_at(addr, &zone.name) {
+                Ok(signed) => return Ok(signed),
+                Err(e) => last_error = Some(format!("{}: {}", ns, e)),
+            },
+            Ok(None) => last_error = Some(format!("{} has no address", ns)),
+            Err(e) => last_error = Some(format!("{}: {}", ns, e)),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| "no nameservers".to_string()).into())
+}
+
+fn signed_at(addr: SocketAddr, zone: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let response = query(addr, zone, hickory_client::rr::RecordType::DNSKEY)?;
+    // A DNSKEY set too large for UDP is still a DNSKEY set.
+    Ok(response.truncated() || response.answers().iter().any(|r| r.record_type() == hickory_client::rr::RecordType::DNSKEY))
+}
+
+/// Queries every nameserver until all of them answer with `value`, or
+/// returns the last mismatch once `deadline` has passed.
+fn wait_until_visible(nameservers: &[String], fqdn: &str, record_type: &str, value: &str, deadline: Option<Instant>, shutdown: &Shutdown) -> Result<(), String> {
+    let expected: IpAddr = value.parse().map_err(|_| format!("{} is not an IP address", value))?;
+    loop {
+        let mismatch = nameservers.iter().find_map(|ns| match query_nameserver(ns, fqdn, record_type) {
+            Ok(answers) if answers.contains(&expected) => None,
+            Ok(answers) if answers.is_empty() => Some(format!("{} has no answer", ns)),
+            Ok(answers) => Some(format!(
+                "{} answers {}",
+                ns,
+                answers.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(", ")
+            )),
+            Err(e) => Some(format!("{} could not be queried: {}", ns, e)),
+        });
+        let Some(mismatch) = mismatch else {
+            return Ok(());
+        };
+        if deadline.is_some_and(|d| Instant::now() + VERIFY_INTERVAL > d) {
+            return Err(mismatch);
+        }
+        debug!("⏳ {} {} not visible yet ({}), checking again in {}s", record_type, fqdn, mismatch, VERIFY_INTERVAL.as_secs());
+        if !shutdown.sleep(VERIFY_INTERVAL) {
+            return Err(format!("{} (stopped checking: shutting down)", mismatch));
+        }
+    }
+}
+
+/// Asks `nameserver` directly (no recursion) for the A or AAAA records of
+/// `fqdn`.
+fn query_nameserver(nameserver: &str, fqdn: &str, record_type: &str) -> Result<Vec<IpAddr>, Box<dyn std::error::Error>> {
+    let addr = (nameserver, 53).to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("{} has no address", nameserver))?;
+    query_address(addr, fqdn, record_type)
+}
+
+fn query_address(addr: SocketAddr, fqdn: &str, record_type: &str) -> Result<Vec<IpAddr>, Box<dyn std::error::Error>> {
+    use hickory_client::rr::{RData, RecordType as DnsRecordType};
+
+    let query_type = if record_type == "AAAA" { DnsRecordType::AAAA } else { DnsRecordType::A };
+    let response = query(addr, fqdn, query_type)?;
+
+    Ok(response.answers().iter()
+        .filter_map(|r| match r.data()? {
+            RData::A(a) => Some(IpAddr::V4(a.0)),
+            RData::AAAA(a) => Some(IpAddr::V6(a.0)),
+            _ => None,
+        })
+        .collect())
+}
+
+fn query(addr: SocketAddr, fqdn: &str, query_type: hickory_client::rr::RecordType) -> Result<hickory_client::proto::xfer::DnsResponse, Box<dyn std::error::Error>> {
+    use hickory_client::client::{Client as _, SyncClient};
+    use hickory_client::rr::{DNSClass, Name};
+    use hickory_client::udp::UdpClientConnection;
+
+    let client = SyncClient::new(UdpClientConnection::with_timeout(addr, DNS_QUERY_TIMEOUT)?);
+    let name = Name::from_ascii(format!("{}.", fqdn))?;
+    Ok(client.query(&name, DNSClass::IN, query_type)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_changes_takes_any_timeout() {
+        let mut report = [RecordReport::new("home.example.com", "TXT", None, Some("hello"), RecordAction::Updated)];
+        assert_eq!(verify_changes(&mut report, &[], Duration::MAX, &Shutdown::default()), 0);
+    }
+
+    #[test]
+    fn signed_at_looks_for_dnskey_records() {
+        use hickory_client::op::{Message, MessageType};
+        use hickory_client::rr::rdata::NULL;
+        use hickory_client::rr::{RData, Record as DnsRecord, RecordType as DnsRecordType};
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            for signed in [true, false] {
+                let mut buf = [0; 512];
+                let (len, peer) = socket.recv_from(&mut buf).unwrap();
+                let query = Message::from_vec(&buf[..len]).unwrap();
+                assert_eq!(query.queries()[0].query_type(), DnsRecordType::DNSKEY);
+                let mut response = Message::new();
+                response.set_id(query.id())
+                    .set_message_type(MessageType::Response)
+                    .add_queries(query.queries().to_vec());
+                if signed {
+                    let key = RData::Unknown { code: DnsRecordType::DNSKEY, rdata: NULL::with(vec![1, 1, 3, 13, 0xab]) };
+                    response.add_answer(DnsRecord::from_rdata(query.queries()[0].name().clone(), 3600, key));
+                }
+                socket.send_to(&response.to_vec().unwrap(), peer).unwrap();
+            }
+        });
+
+        assert!(signed_at(addr, "example.com").unwrap());
+        assert!(!signed_at(addr, "example.com").unwrap());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn query_address_reads_the_authoritative_answer() {
+        use hickory_client::op::{Message, MessageType};
+        use hickory_client::rr::{rdata, RData, Record as DnsRecord};
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, peer) = socket.recv_from(&mut buf).unwrap();
+            let query = Message::from_vec(&buf[..len]).unwrap();
+            let name = query.queries()[0].name().clone();
+            let mut response = Message::new();
+            response.set_id(query.id())
+                .set_message_type(MessageType::Response)
+                .add_queries(query.queries().to_vec())
+                .add_answer(DnsRecord::from_rdata(name, 60, RData::A(rdata::A("192.0.2.9".parse().unwrap()))));
+            socket.send_to(&response.to_vec().unwrap(), peer).unwrap();
+        });
+
+        let answers = query_address(addr, "home.example.com", "A").unwrap();
+        server.join().unwrap();
+        assert_eq!(answers, vec!["192.0.2.9".parse::<IpAddr>().unwrap()]);
+    }
+}
@@ -0,0 +1,120 @@
+use std::net::IpAddr;
+
+use hetzner_ddns::{ApiRequestError, EnsureOutcome, HetznerDnsClient, Record, RecordType, USER_AGENT};
+use mockito::{Matcher, Server, ServerGuard};
+
+fn client(server: &ServerGuard) -> HetznerDnsClient {
+    HetznerDnsClient::new("token")
+        .with_base_url(server.url())
+        .with_rate_limit(1000.0)
+}
+
+fn mock_zone(server: &mut ServerGuard) {
+    server.mock("GET", "/zones")
+        .match_query(Matcher::UrlEncoded("name".into(), "home.example.com".into()))
+        .with_status(404)
+        .with_body(r#"{"error":{"message":"zone not found","code":404}}"#)
+        .create();
+    server.mock("GET", "/zones")
+        .match_query(Matcher::UrlEncoded("name".into(), "example.com".into()))
+        .with_body(r#"{"zones":[{"id":"z1","name":"example.com"}]}"#)
+        .create();
+}
+
+fn mock_records(server: &mut ServerGuard, value: &str) {
+    server.mock("GET", "/records")
+        .match_query(Matcher::UrlEncoded("zone_id".into(), "z1".into()))
+        .with_body(format!(
+            r#"{{"records":[{{"id":"r1","type":"A","name":"home","value":"{}","zone_id":"z1","ttl":60}}]}}"#,
+            value
+        ))
+        .create();
+}
+
+fn ip(value: &str) -> IpAddr {
+    value.parse().unwrap()
+}
+
+#[test]
+fn ensure_record_updates_a_stale_value() {
+    let mut server = Server::new();
+    mock_zone(&mut server);
+    mock_records(&mut server, "1.1.1.1");
+    let put = server.mock("PUT", "/records/r1")
+        .match_header("Auth-API-Token", "token")
+        .match_header("User-Agent", USER_AGENT)
+        .match_body(Matcher::PartialJsonString(r#"{"value":"203.0.113.7","ttl":60}"#.into()))
+        .with_body(r#"{"record":{"id":"r1","type":"A","name":"home","value":"203.0.113.7","zone_id":"z1","ttl":60}}"#)
+        .create();
+
+    let outcome = client(&server)
+        .ensure_record("home.example.com", ip("203.0.113.7"), RecordType::A)
+        .unwrap();
+
+    put.assert();
+    assert_eq!(outcome, EnsureOutcome::Updated { old_value: "1.1.1.1".into() });
+}
+
+#[test]
+fn ensure_record_leaves_a_current_value_alone() {
+    let mut server = Server::new();
+    mock_zone(&mut server);
+    mock_records(&mut server, "203.0.113.7");
+    let put = server.mock("PUT", Matcher::Any).expect(0).create();
+
+    let outcome = client(&server)
+        .ensure_record("home.example.com", ip("203.0.113.7"), RecordType::A)
+        .unwrap();
+
+    put.assert();
+    assert_eq!(outcome, EnsureOutcome::Unchanged);
+}
+
+#[test]
+fn ensure_record_reports_api_errors() {
+    let mut server = Server::new();
+    server.mock("GET", "/zones")
+        .match_query(Matcher::Any)
+        .with_status(401)
+        .with_body(r#"{"error":{"message":"invalid authentication credentials","code":401}}"#)
+        .create();
+
+    let err = client(&server)
+        .ensure_record("home.example.com", ip("203.0.113.7"), RecordType::A)
+        .unwrap_err();
+
+    let err = err.downcast_ref::<ApiRequestError>().expect("typed API error");
+    assert!(err.is_auth());
+    assert_eq!(err.status().as_u16(), 401);
+}
+
+#[test]
+fn ensure_record_rejects_mismatched_address_family() {
+    let server = Server::new();
+    let err = client(&server)
+        .ensure_record("home.example.com", ip("2001:db8::1"), RecordType::A)
+        .unwrap_err();
+
+    assert!(err.to_string().contains("not an address for a A record"));
+}
+
+#[test]
+fn delete_record_sends_delete_for_the_record_id() {
+    let mut server = Server::new();
+    let delete = server.mock("DELETE", "/records/r4")
+        .match_header("Auth-API-Token", "token")
+        .with_body("{}")
+        .create();
+    let record = Record {
+        id: "r4".into(),
+        record_type: "AAAA".into(),
+        name: "home".into(),
+        value: "2001:db8::1".into(),
+        zone_id: "z1".into(),
+        ttl: None,
+    };
+
+    client(&server).delete_record(&record).unwrap();
+
+    delete.assert();
+}